@@ -1,17 +1,21 @@
-///! *abi_impl.rs* contains all the implementation (and some tools as
-///! abi_bail!) of the massa abi.
-///!
-///! The ABIs are the imported function / object declared in the webassembly
-///! module. You can look at the other side of the mirror in `massa.ts` and the
-///! rust side in `execution_impl.rs`.
-///!
-///! ```
+//! *abi_impl.rs* contains all the implementation (and some tools as
+//! abi_bail!) of the massa abi.
+//!
+//! The ABIs are the imported function / object declared in the webassembly
+//! module. You can look at the other side of the mirror in `massa.ts` and the
+//! rust side in `execution_impl.rs`.
+
 use crate::env::{
-    get_remaining_points, set_remaining_points, sub_remaining_gas, sub_remaining_gas_with_mult, Env,
+    count_host_call, get_remaining_points, get_stack_height, set_remaining_points,
+    sub_remaining_gas, sub_remaining_gas_with_mult, CallContext, Env,
 };
+use crate::error::{ExecutionError, ReadOnlyError};
+use crate::gas_report::GasReport;
 use crate::settings;
-use crate::types::Response;
-use as_ffi_bindings::{Read as ASRead, StringPtr, Write as ASWrite};
+use crate::types::{ExecutionResult, ReentrancyPolicy, ReturnValue, ScEvent};
+use crate::validation::validate_module;
+use as_ffi_bindings::{AnyPtr, Read as ASRead, StringPtr, Write as ASWrite};
+use std::sync::atomic::Ordering;
 use wasmer::Memory;
 
 pub type ABIResult<T, E = wasmer::RuntimeError> = core::result::Result<T, E>;
@@ -38,45 +42,163 @@ pub(crate) use get_memory;
 /// It take in argument the environment defined in env.rs
 /// this environment is automatically filled by the wasmer library
 /// And two pointers of string. (look at the readme in the wasm folder)
+///
+/// The call fails if it exceeds `max_call_depth`, or if the callee is already
+/// on the call stack while the call or the callee denies reentrancy.
+///
+/// The error of the callee is returned in the inner result, after the call
+/// has been reverted: the caller decides whether to forward it or to catch it.
 fn call_module(
     env: &Env,
     address: &str,
     function: &str,
     param: &str,
-    raw_coins: i64,
-) -> ABIResult<Response> {
-    let raw_coins: u64 = match raw_coins.try_into() {
+    options: CallOptions,
+) -> ABIResult<Result<ExecutionResult, ExecutionError>> {
+    let raw_coins: u64 = match options.raw_coins.try_into() {
         Ok(v) => v,
         Err(_) => abi_bail!("negative amount of coins in Call"),
     };
+    let read_only = env.context.read_only || options.read_only;
+    if read_only && raw_coins > 0 {
+        abi_bail!("coins can't be transferred by a read-only call");
+    }
+    let remaining_gas = get_remaining_points(env)?;
+    let limit = std::cmp::min(options.max_gas, remaining_gas);
+    if env.context.call_depth >= env.gas_costs.max_call_depth {
+        return Ok(Err(ExecutionError::CallDepth {
+            function: function.to_string(),
+            remaining_gas: limit,
+            max_call_depth: env.gas_costs.max_call_depth,
+        }));
+    }
+    let reentrancy = match env.interface.get_reentrancy_policy(address) {
+        Ok(ReentrancyPolicy::Allow) => options.reentrancy,
+        Ok(ReentrancyPolicy::Deny) => ReentrancyPolicy::Deny,
+        Err(err) => abi_bail!(err),
+    };
+    if reentrancy == ReentrancyPolicy::Deny {
+        match env.interface.get_call_stack() {
+            Ok(call_stack) if call_stack.iter().any(|caller| caller == address) => {
+                return Ok(Err(ExecutionError::Reentrancy {
+                    function: function.to_string(),
+                    remaining_gas: limit,
+                    address: address.to_string(),
+                }))
+            }
+            Ok(_) => (),
+            Err(err) => abi_bail!(err),
+        }
+    }
+    let stack_height = get_stack_height(env)?;
+    let memory_pages = env
+        .context
+        .memory_pages
+        .saturating_add(get_memory!(env).size().0);
     let module = &match env.interface.init_call(address, raw_coins) {
         Ok(module) => module,
-        Err(err) => abi_bail!(err),
+        Err(err) => {
+            return Ok(Err(ExecutionError::Interface {
+                function: function.to_string(),
+                remaining_gas: limit,
+                error: err.to_string(),
+            }))
+        }
     };
-    match crate::execution_impl::exec(
-        get_remaining_points(env)?,
-        None,
-        module,
-        function,
-        param,
-        &*env.interface,
-    ) {
+    let context = CallContext {
+        call_depth: env.context.call_depth + 1,
+        read_only,
+        stack_height,
+        memory_pages,
+        ..env.context
+    };
+    let mut callee_env = Env::new(&*env.interface, env.gas_costs.clone(), context);
+    callee_env.tracer = env.tracer.clone();
+    callee_env.event_count = env.event_count.clone();
+    callee_env.deadline = env.deadline.clone();
+    if let Some(tracer) = &env.tracer {
+        tracer.enter_call(address, function, param, limit, context.call_depth);
+    }
+    let result = crate::execution_impl::exec(limit, None, module, function, param, callee_env);
+    if let Some(tracer) = &env.tracer {
+        tracer.exit_call(address, function, result.as_ref(), context.call_depth);
+    }
+    if let Some(gas_report) = &env.gas_report {
+        let call_report = match &result {
+            Ok(resp) => resp.gas_report.clone().unwrap_or_default(),
+            Err(err) => GasReport {
+                function: function.to_string(),
+                gas_used: limit.saturating_sub(err.remaining_gas()),
+                ..Default::default()
+            },
+        };
+        gas_report.lock().unwrap().calls.push(GasReport {
+            address: Some(address.to_string()),
+            ..call_report
+        });
+    }
+    match result {
         Ok(resp) => {
-            if let Err(err) = set_remaining_points(env, resp.remaining_gas) {
+            let gas_used = limit.saturating_sub(resp.remaining_gas);
+            if let Err(err) = set_remaining_points(env, remaining_gas - gas_used) {
                 abi_bail!(err);
             }
             match env.interface.finish_call() {
-                Ok(_) => Ok(resp),
+                Ok(_) => Ok(Ok(resp)),
                 Err(err) => abi_bail!(err),
             }
         }
-        Err(err) => abi_bail!(err),
+        Err(err) => {
+            if let Err(revert_err) = env.interface.revert_call() {
+                abi_bail!(format!(
+                    "{}, and the call can't be reverted: {}",
+                    err, revert_err
+                ))
+            }
+            let gas_used = limit.saturating_sub(err.remaining_gas());
+            set_remaining_points(env, remaining_gas - gas_used)?;
+            Ok(Err(err))
+        }
+    }
+}
+
+/// How `call_module` calls a module
+struct CallOptions {
+    raw_coins: i64,
+    /// The callee gets at most `max_gas`, and only the gas it used is charged
+    /// to the caller, even if it fails
+    max_gas: u64,
+    reentrancy: ReentrancyPolicy,
+    /// The callee, and the modules it calls, can't change the state
+    read_only: bool,
+}
+
+impl CallOptions {
+    fn new(raw_coins: i64) -> Self {
+        CallOptions {
+            raw_coins,
+            max_gas: u64::MAX,
+            reentrancy: ReentrancyPolicy::Allow,
+            read_only: false,
+        }
+    }
+}
+
+/// Fail if the execution is read-only, see `run_function_readonly`
+///
+/// Called first by the host functions changing the state.
+fn check_writable(env: &Env, host_function: &str) -> ABIResult<()> {
+    if env.context.read_only {
+        return Err(wasmer::RuntimeError::user(Box::new(ReadOnlyError {
+            host_function: host_function.to_string(),
+        })));
     }
+    Ok(())
 }
 
 /// Get the coins that have been made available for a specific purpose for the current call.
 pub(crate) fn assembly_script_get_call_coins(env: &Env) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_get_call_coins())?;
+    sub_remaining_gas(env, env.gas_costs.get_call_coins)?;
     match env.interface.get_call_coins() {
         Ok(res) => Ok(res as i64),
         Err(err) => abi_bail!(err),
@@ -89,7 +211,8 @@ pub(crate) fn assembly_script_transfer_coins(
     to_address: i32,
     raw_amount: i64,
 ) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_transfer())?;
+    check_writable(env, "assembly_script_transfer_coins")?;
+    sub_remaining_gas(env, env.gas_costs.transfer)?;
     if raw_amount.is_negative() {
         abi_bail!("Negative raw amount.");
     }
@@ -108,7 +231,8 @@ pub(crate) fn assembly_script_transfer_coins_for(
     to_address: i32,
     raw_amount: i64,
 ) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_transfer())?;
+    check_writable(env, "assembly_script_transfer_coins_for")?;
+    sub_remaining_gas(env, env.gas_costs.transfer)?;
     if raw_amount.is_negative() {
         abi_bail!("Negative raw amount.");
     }
@@ -125,7 +249,7 @@ pub(crate) fn assembly_script_transfer_coins_for(
 }
 
 pub(crate) fn assembly_script_get_balance(env: &Env) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_get_balance())?;
+    sub_remaining_gas(env, env.gas_costs.get_balance)?;
     match env.interface.get_balance() {
         Ok(res) => Ok(res as i64),
         Err(err) => abi_bail!(err),
@@ -133,7 +257,7 @@ pub(crate) fn assembly_script_get_balance(env: &Env) -> ABIResult<i64> {
 }
 
 pub(crate) fn assembly_script_get_balance_for(env: &Env, address: i32) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_get_balance())?;
+    sub_remaining_gas(env, env.gas_costs.get_balance)?;
     let memory = get_memory!(env);
     let address = &get_string(memory, address)?;
     match env.interface.get_balance_for(address) {
@@ -143,6 +267,9 @@ pub(crate) fn assembly_script_get_balance_for(env: &Env, address: i32) -> ABIRes
 }
 
 fn create_sc(env: &Env, bytecode: &[u8]) -> ABIResult<String> {
+    if let Err(err) = validate_module(bytecode, &env.gas_costs) {
+        abi_bail!(err)
+    }
     match env.interface.create_module(bytecode) {
         Ok(address) => Ok(address),
         Err(err) => abi_bail!(err),
@@ -159,14 +286,185 @@ pub(crate) fn assembly_script_call_module(
     param: i32,
     call_coins: i64,
 ) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_call())?;
+    call(env, address, function, param, CallOptions::new(call_coins))
+}
+
+/// Same as `assembly_script_call_module`, failing if the callee is already
+/// on the call stack
+pub(crate) fn assembly_script_call_non_reentrant(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+    call_coins: i64,
+) -> ABIResult<i32> {
+    let options = CallOptions {
+        reentrancy: ReentrancyPolicy::Deny,
+        ..CallOptions::new(call_coins)
+    };
+    call(env, address, function, param, options)
+}
+
+/// Same as `assembly_script_call_module`, the callee getting at most
+/// `max_gas`
+pub(crate) fn assembly_script_call_with_gas(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+    call_coins: i64,
+    max_gas: i64,
+) -> ABIResult<i32> {
+    let options = CallOptions {
+        max_gas: max_gas_from_i64(max_gas)?,
+        ..CallOptions::new(call_coins)
+    };
+    call(env, address, function, param, options)
+}
+
+/// Same as `assembly_script_call_module` without coins, the callee and the
+/// modules it calls failing if they try to change the state
+pub(crate) fn assembly_script_static_call(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+) -> ABIResult<i32> {
+    let options = CallOptions {
+        read_only: true,
+        ..CallOptions::new(0)
+    };
+    call(env, address, function, param, options)
+}
+
+fn call(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+    options: CallOptions,
+) -> ABIResult<i32> {
+    sub_remaining_gas(env, env.gas_costs.call)?;
     let memory = get_memory!(env);
     let address = &get_string(memory, address)?;
     let function = &get_string(memory, function)?;
     let param = &get_string(memory, param)?;
-    let response = call_module(env, address, function, param, call_coins)?;
-    match StringPtr::alloc(&response.ret, &env.wasm_env) {
-        Ok(ret) => Ok(ret.offset() as i32),
+    match call_module(env, address, function, param, options)? {
+        Ok(response) => alloc_return_value(env, response.ret, address, function),
+        // Forward the error of the callee, see `ExecutionError`
+        Err(err) => Err(wasmer::RuntimeError::user(Box::new(err))),
+    }
+}
+
+/// Call an exported function in a WASM module at a given address, without
+/// failing if the callee fails
+///
+/// Returns 0 if the call succeeded, the value returned by the callee being
+/// written at `result`. Otherwise returns the code of the error (see
+/// `ExecutionError::code`), its message being written at `result`. In both
+/// cases `result` is the address of a pointer, an `usize` in AssemblyScript.
+///
+/// The changes made by a failed callee are reverted by the interface, see
+/// `JournaledInterface`. An `ExecutionError::Timeout` isn't caught, as it
+/// depends on the host.
+///
+/// The callee can't use `GasCosts::try_call_reserved_gas`, which is kept for
+/// the caller to handle its failure.
+pub(crate) fn assembly_script_try_call(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+    call_coins: i64,
+    result: i32,
+) -> ABIResult<i32> {
+    let options = CallOptions::new(call_coins);
+    try_call(env, address, function, param, options, result)
+}
+
+/// Same as `assembly_script_try_call`, the callee getting at most `max_gas`
+///
+/// The caller keeps the gas the callee didn't use to handle its failure.
+pub(crate) fn assembly_script_try_call_with_gas(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+    call_coins: i64,
+    max_gas: i64,
+    result: i32,
+) -> ABIResult<i32> {
+    let options = CallOptions {
+        max_gas: max_gas_from_i64(max_gas)?,
+        ..CallOptions::new(call_coins)
+    };
+    try_call(env, address, function, param, options, result)
+}
+
+fn try_call(
+    env: &Env,
+    address: i32,
+    function: i32,
+    param: i32,
+    options: CallOptions,
+    result: i32,
+) -> ABIResult<i32> {
+    sub_remaining_gas(env, env.gas_costs.call)?;
+    let memory = get_memory!(env);
+    let address = &get_string(memory, address)?;
+    let function = &get_string(memory, function)?;
+    let param = &get_string(memory, param)?;
+    let available_gas =
+        get_remaining_points(env)?.saturating_sub(env.gas_costs.try_call_reserved_gas);
+    let options = CallOptions {
+        max_gas: options.max_gas.min(available_gas),
+        ..options
+    };
+    let (code, offset) = match call_module(env, address, function, param, options)? {
+        Ok(response) => (0, alloc_return_value(env, response.ret, address, function)?),
+        // The timeout depends on the host, the caller mustn't be able to
+        // catch it
+        Err(err @ ExecutionError::Timeout { .. }) => {
+            return Err(wasmer::RuntimeError::user(Box::new(err)))
+        }
+        Err(err) => (
+            err.code(),
+            pointer_from_string(env, &err.to_string())?.offset() as i32,
+        ),
+    };
+    write_pointer(memory, result, offset)?;
+    Ok(code)
+}
+
+fn max_gas_from_i64(max_gas: i64) -> ABIResult<u64> {
+    match max_gas.try_into() {
+        Ok(max_gas) => Ok(max_gas),
+        Err(_) => abi_bail!("negative max gas in Call"),
+    }
+}
+
+/// Allocate the value returned by a called module in the memory of the caller
+fn alloc_return_value(
+    env: &Env,
+    ret: ReturnValue,
+    address: &str,
+    function: &str,
+) -> ABIResult<i32> {
+    // Numbers are returned as strings, as the `0` of a `main` used to be
+    let ret = match ret {
+        ReturnValue::Empty => {
+            StringPtr::alloc(&String::new(), &env.wasm_env).map(|ptr| ptr.offset())
+        }
+        ReturnValue::Int(value) => {
+            StringPtr::alloc(&value.to_string(), &env.wasm_env).map(|ptr| ptr.offset())
+        }
+        ReturnValue::String(value) => {
+            StringPtr::alloc(&value, &env.wasm_env).map(|ptr| ptr.offset())
+        }
+        ReturnValue::Bytes(value) => AnyPtr::alloc(&value, &env.wasm_env).map(|ptr| ptr.offset()),
+    };
+    match ret {
+        Ok(offset) => Ok(offset as i32),
         _ => abi_bail!(format!(
             "Cannot allocate response in call {}::{}",
             address, function
@@ -175,7 +473,7 @@ pub(crate) fn assembly_script_call_module(
 }
 
 pub(crate) fn assembly_script_get_remaining_gas(env: &Env) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_remaining_gas())?;
+    sub_remaining_gas(env, env.gas_costs.remaining_gas)?;
     Ok(get_remaining_points(env)? as i64)
 }
 
@@ -184,7 +482,7 @@ pub(crate) fn assembly_script_get_remaining_gas(env: &Env) -> ABIResult<i64> {
 ///
 /// An utility print function to write on stdout directly from AssemblyScript:
 pub(crate) fn assembly_script_print(env: &Env, arg: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_print())?;
+    sub_remaining_gas(env, env.gas_costs.print)?;
     let memory = get_memory!(env);
     if let Err(err) = env.interface.print(&get_string(memory, arg)?) {
         abi_bail!(err);
@@ -195,13 +493,15 @@ pub(crate) fn assembly_script_print(env: &Env, arg: i32) -> ABIResult<()> {
 /// Read a bytecode string, representing the webassembly module binary encoded
 /// with in base64.
 pub(crate) fn assembly_script_create_sc(env: &Env, bytecode: i32) -> ABIResult<i32> {
+    check_writable(env, "assembly_script_create_sc")?;
+    count_host_call(env);
     let memory = get_memory!(env);
     // Base64 to Binary
     let bytecode = match base64::decode(read_string_and_sub_gas(
         env,
         memory,
         bytecode,
-        settings::metering_create_sc_mult(),
+        env.gas_costs.create_sc_mult,
     )?) {
         Ok(bytecode) => bytecode,
         Err(err) => abi_bail!(err),
@@ -218,9 +518,9 @@ pub(crate) fn assembly_script_create_sc(env: &Env, bytecode: i32) -> ABIResult<i
 
 /// performs a hash on a string and returns the bs58check encoded hash
 pub(crate) fn assembly_script_hash(env: &Env, value: i32) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_hash_const())?;
+    sub_remaining_gas(env, env.gas_costs.get_hash_const)?;
     let memory = get_memory!(env);
-    let value = read_string_and_sub_gas(env, memory, value, settings::metering_hash_per_byte())?;
+    let value = read_string_and_sub_gas(env, memory, value, env.gas_costs.hash_per_byte)?;
     match env.interface.hash(value.as_bytes()) {
         Ok(h) => Ok(pointer_from_string(env, &h)?.offset() as i32),
         Err(err) => abi_bail!(err),
@@ -229,11 +529,11 @@ pub(crate) fn assembly_script_hash(env: &Env, value: i32) -> ABIResult<i32> {
 
 /// sets a key-indexed data entry in the datastore, overwriting existing values if any
 pub(crate) fn assembly_script_set_data(env: &Env, key: i32, value: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_set_data_const())?;
+    check_writable(env, "assembly_script_set_data")?;
+    sub_remaining_gas(env, env.gas_costs.set_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_set_data_key_mult())?;
-    let value =
-        read_string_and_sub_gas(env, memory, value, settings::metering_set_data_value_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.set_data_key_mult)?;
+    let value = read_string_and_sub_gas(env, memory, value, env.gas_costs.set_data_value_mult)?;
     if let Err(err) = env.interface.raw_set_data(&key, value.as_bytes()) {
         abi_bail!(err)
     }
@@ -242,15 +542,11 @@ pub(crate) fn assembly_script_set_data(env: &Env, key: i32, value: i32) -> ABIRe
 
 /// appends data to a key-indexed data entry in the datastore, fails if the entry does not exist
 pub(crate) fn assembly_script_append_data(env: &Env, key: i32, value: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_append_data_const())?;
+    check_writable(env, "assembly_script_append_data")?;
+    sub_remaining_gas(env, env.gas_costs.append_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_append_data_key_mult())?;
-    let value = read_string_and_sub_gas(
-        env,
-        memory,
-        value,
-        settings::metering_append_data_value_mult(),
-    )?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.append_data_key_mult)?;
+    let value = read_string_and_sub_gas(env, memory, value, env.gas_costs.append_data_value_mult)?;
     if let Err(err) = env.interface.raw_append_data(&key, value.as_bytes()) {
         abi_bail!(err)
     }
@@ -259,12 +555,12 @@ pub(crate) fn assembly_script_append_data(env: &Env, key: i32, value: i32) -> AB
 
 /// gets a key-indexed data entry in the datastore, failing if non-existant
 pub(crate) fn assembly_script_get_data(env: &Env, key: i32) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_data_const())?;
+    sub_remaining_gas(env, env.gas_costs.get_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_get_data_key_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.get_data_key_mult)?;
     match env.interface.raw_get_data(&key) {
         Ok(data) => {
-            sub_remaining_gas_with_mult(env, data.len(), settings::metering_get_data_value_mult())?;
+            sub_remaining_gas_with_mult(env, data.len(), env.gas_costs.get_data_value_mult)?;
             Ok(pointer_from_utf8(env, &data)?.offset() as i32)
         }
         Err(err) => abi_bail!(err),
@@ -273,9 +569,9 @@ pub(crate) fn assembly_script_get_data(env: &Env, key: i32) -> ABIResult<i32> {
 
 /// checks if a key-indexed data entry exists in the datastore
 pub(crate) fn assembly_script_has_data(env: &Env, key: i32) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_has_data_const())?;
+    sub_remaining_gas(env, env.gas_costs.has_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_has_data_key_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.has_data_key_mult)?;
     match env.interface.has_data(&key) {
         Ok(true) => Ok(1),
         Ok(false) => Ok(0),
@@ -285,15 +581,56 @@ pub(crate) fn assembly_script_has_data(env: &Env, key: i32) -> ABIResult<i32> {
 
 /// deletes a key-indexed data entry in the datastore of the current address, fails if the entry is absent
 pub(crate) fn assembly_script_delete_data(env: &Env, key: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_delete_data_const())?;
+    check_writable(env, "assembly_script_delete_data")?;
+    sub_remaining_gas(env, env.gas_costs.delete_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_delete_data_key_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.delete_data_key_mult)?;
     match env.interface.raw_delete_data(&key) {
         Ok(_) => Ok(()),
         Err(err) => abi_bail!(err),
     }
 }
 
+/// sets a key-indexed data entry in the datastore with a raw `ArrayBuffer` value, overwriting existing values if any
+pub(crate) fn assembly_script_set_data_bytes(env: &Env, key: i32, value: i32) -> ABIResult<()> {
+    check_writable(env, "assembly_script_set_data_bytes")?;
+    sub_remaining_gas(env, env.gas_costs.set_data_const)?;
+    let memory = get_memory!(env);
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.set_data_key_mult)?;
+    let value = read_buffer_and_sub_gas(env, memory, value, env.gas_costs.set_data_value_mult)?;
+    if let Err(err) = env.interface.raw_set_data(&key, &value) {
+        abi_bail!(err)
+    }
+    Ok(())
+}
+
+/// appends raw bytes to a key-indexed data entry in the datastore, fails if the entry does not exist
+pub(crate) fn assembly_script_append_data_bytes(env: &Env, key: i32, value: i32) -> ABIResult<()> {
+    check_writable(env, "assembly_script_append_data_bytes")?;
+    sub_remaining_gas(env, env.gas_costs.append_data_const)?;
+    let memory = get_memory!(env);
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.append_data_key_mult)?;
+    let value = read_buffer_and_sub_gas(env, memory, value, env.gas_costs.append_data_value_mult)?;
+    if let Err(err) = env.interface.raw_append_data(&key, &value) {
+        abi_bail!(err)
+    }
+    Ok(())
+}
+
+/// gets a key-indexed data entry in the datastore as an `ArrayBuffer`, failing if non-existant
+pub(crate) fn assembly_script_get_data_bytes(env: &Env, key: i32) -> ABIResult<i32> {
+    sub_remaining_gas(env, env.gas_costs.get_data_const)?;
+    let memory = get_memory!(env);
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.get_data_key_mult)?;
+    match env.interface.raw_get_data(&key) {
+        Ok(data) => {
+            sub_remaining_gas_with_mult(env, data.len(), env.gas_costs.get_data_value_mult)?;
+            Ok(pointer_from_bytes(env, &data)?.offset() as i32)
+        }
+        Err(err) => abi_bail!(err),
+    }
+}
+
 /// Sets the value of a datastore entry of an arbitrary address, creating the entry if it does not exist.
 /// Fails if the address does not exist.
 pub(crate) fn assembly_script_set_data_for(
@@ -302,11 +639,11 @@ pub(crate) fn assembly_script_set_data_for(
     key: i32,
     value: i32,
 ) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_set_data_const())?;
+    check_writable(env, "assembly_script_set_data_for")?;
+    sub_remaining_gas(env, env.gas_costs.set_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_set_data_key_mult())?;
-    let value =
-        read_string_and_sub_gas(env, memory, value, settings::metering_set_data_value_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.set_data_key_mult)?;
+    let value = read_string_and_sub_gas(env, memory, value, env.gas_costs.set_data_value_mult)?;
     let address = get_string(memory, address)?;
     if let Err(err) = env
         .interface
@@ -324,15 +661,11 @@ pub(crate) fn assembly_script_append_data_for(
     key: i32,
     value: i32,
 ) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_append_data_const())?;
+    check_writable(env, "assembly_script_append_data_for")?;
+    sub_remaining_gas(env, env.gas_costs.append_data_const)?;
     let memory = get_memory!(env);
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_append_data_key_mult())?;
-    let value = read_string_and_sub_gas(
-        env,
-        memory,
-        value,
-        settings::metering_append_data_value_mult(),
-    )?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.append_data_key_mult)?;
+    let value = read_string_and_sub_gas(env, memory, value, env.gas_costs.append_data_value_mult)?;
     let address = get_string(memory, address)?;
     if let Err(err) = env
         .interface
@@ -345,25 +678,84 @@ pub(crate) fn assembly_script_append_data_for(
 
 /// Gets the value of a datastore entry for an arbitrary address, fails if the entry or address does not exist
 pub(crate) fn assembly_script_get_data_for(env: &Env, address: i32, key: i32) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_data_const())?;
+    sub_remaining_gas(env, env.gas_costs.get_data_const)?;
     let memory = get_memory!(env);
     let address = get_string(memory, address)?;
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_get_data_key_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.get_data_key_mult)?;
     match env.interface.raw_get_data_for(&address, &key) {
         Ok(data) => {
-            sub_remaining_gas_with_mult(env, data.len(), settings::metering_get_data_value_mult())?;
+            sub_remaining_gas_with_mult(env, data.len(), env.gas_costs.get_data_value_mult)?;
             Ok(pointer_from_utf8(env, &data)?.offset() as i32)
         }
         Err(err) => abi_bail!(err),
     }
 }
 
+/// Sets the raw `ArrayBuffer` value of a datastore entry of an arbitrary address, creating the entry if it does not exist.
+/// Fails if the address does not exist.
+pub(crate) fn assembly_script_set_data_bytes_for(
+    env: &Env,
+    address: i32,
+    key: i32,
+    value: i32,
+) -> ABIResult<()> {
+    check_writable(env, "assembly_script_set_data_bytes_for")?;
+    sub_remaining_gas(env, env.gas_costs.set_data_const)?;
+    let memory = get_memory!(env);
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.set_data_key_mult)?;
+    let value = read_buffer_and_sub_gas(env, memory, value, env.gas_costs.set_data_value_mult)?;
+    let address = get_string(memory, address)?;
+    if let Err(err) = env.interface.raw_set_data_for(&address, &key, &value) {
+        abi_bail!(err)
+    }
+    Ok(())
+}
+
+/// Appends raw bytes to the value of a datastore entry of an arbitrary address, fails if the entry or address does not exist.
+pub(crate) fn assembly_script_append_data_bytes_for(
+    env: &Env,
+    address: i32,
+    key: i32,
+    value: i32,
+) -> ABIResult<()> {
+    check_writable(env, "assembly_script_append_data_bytes_for")?;
+    sub_remaining_gas(env, env.gas_costs.append_data_const)?;
+    let memory = get_memory!(env);
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.append_data_key_mult)?;
+    let value = read_buffer_and_sub_gas(env, memory, value, env.gas_costs.append_data_value_mult)?;
+    let address = get_string(memory, address)?;
+    if let Err(err) = env.interface.raw_append_data_for(&address, &key, &value) {
+        abi_bail!(err)
+    }
+    Ok(())
+}
+
+/// Gets the value of a datastore entry for an arbitrary address as an `ArrayBuffer`, fails if the entry or address does not exist
+pub(crate) fn assembly_script_get_data_bytes_for(
+    env: &Env,
+    address: i32,
+    key: i32,
+) -> ABIResult<i32> {
+    sub_remaining_gas(env, env.gas_costs.get_data_const)?;
+    let memory = get_memory!(env);
+    let address = get_string(memory, address)?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.get_data_key_mult)?;
+    match env.interface.raw_get_data_for(&address, &key) {
+        Ok(data) => {
+            sub_remaining_gas_with_mult(env, data.len(), env.gas_costs.get_data_value_mult)?;
+            Ok(pointer_from_bytes(env, &data)?.offset() as i32)
+        }
+        Err(err) => abi_bail!(err),
+    }
+}
+
 /// Deletes a datastore entry for an address. Fails if the entry or address does not exist.
 pub(crate) fn assembly_script_delete_data_for(env: &Env, address: i32, key: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_delete_data_const())?;
+    check_writable(env, "assembly_script_delete_data_for")?;
+    sub_remaining_gas(env, env.gas_costs.delete_data_const)?;
     let memory = get_memory!(env);
     let address = get_string(memory, address)?;
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_delete_data_key_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.delete_data_key_mult)?;
     match env.interface.raw_delete_data_for(&address, &key) {
         Ok(_) => Ok(()),
         Err(err) => abi_bail!(err),
@@ -371,10 +763,10 @@ pub(crate) fn assembly_script_delete_data_for(env: &Env, address: i32, key: i32)
 }
 
 pub(crate) fn assembly_script_has_data_for(env: &Env, address: i32, key: i32) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_has_data_const())?;
+    sub_remaining_gas(env, env.gas_costs.has_data_const)?;
     let memory = get_memory!(env);
     let address = get_string(memory, address)?;
-    let key = read_string_and_sub_gas(env, memory, key, settings::metering_has_data_key_mult())?;
+    let key = read_string_and_sub_gas(env, memory, key, env.gas_costs.has_data_key_mult)?;
     match env.interface.has_data_for(&address, &key) {
         Ok(true) => Ok(1),
         Ok(false) => Ok(0),
@@ -382,8 +774,63 @@ pub(crate) fn assembly_script_has_data_for(env: &Env, address: i32, key: i32) ->
     }
 }
 
+/// Lists the keys of the datastore of the current address that start with a
+/// prefix, sorted, returning at most `count` keys from the `offset`-th one
+/// as a json list
+pub(crate) fn assembly_script_get_keys(
+    env: &Env,
+    prefix: i32,
+    offset: i32,
+    count: i32,
+) -> ABIResult<i32> {
+    sub_remaining_gas(env, env.gas_costs.get_keys_const)?;
+    let memory = get_memory!(env);
+    let prefix = read_string_and_sub_gas(env, memory, prefix, env.gas_costs.get_keys_prefix_mult)?;
+    match env.interface.get_keys(&prefix) {
+        Ok(keys) => alloc_keys_page(env, keys, offset, count),
+        Err(err) => abi_bail!(err),
+    }
+}
+
+/// Lists the keys of the datastore of an arbitrary address that start with a
+/// prefix, see `assembly_script_get_keys`
+pub(crate) fn assembly_script_get_keys_for(
+    env: &Env,
+    address: i32,
+    prefix: i32,
+    offset: i32,
+    count: i32,
+) -> ABIResult<i32> {
+    sub_remaining_gas(env, env.gas_costs.get_keys_const)?;
+    let memory = get_memory!(env);
+    let address = get_string(memory, address)?;
+    let prefix = read_string_and_sub_gas(env, memory, prefix, env.gas_costs.get_keys_prefix_mult)?;
+    match env.interface.get_keys_for(&address, &prefix) {
+        Ok(keys) => alloc_keys_page(env, keys, offset, count),
+        Err(err) => abi_bail!(err),
+    }
+}
+
+/// Tooling, charge and return a page of keys as a json list
+///
+/// Every key given by the interface is charged, not only the ones of the
+/// page: they're all sorted to find the page.
+///
+/// Sub function of `assembly_script_get_keys` and `assembly_script_get_keys_for`
+fn alloc_keys_page(env: &Env, mut keys: Vec<String>, offset: i32, count: i32) -> ABIResult<i32> {
+    let (offset, count) = match (usize::try_from(offset), usize::try_from(count)) {
+        (Ok(offset), Ok(count)) => (offset, count),
+        _ => abi_bail!("negative offset or count in get_keys"),
+    };
+    sub_remaining_gas_with_mult(env, keys.len(), env.gas_costs.get_keys_per_key)?;
+    // Sort the keys so the pages don't depend on the order of the interface
+    keys.sort_unstable();
+    let page: Vec<String> = keys.into_iter().skip(offset).take(count).collect();
+    alloc_string_array(env, &page)
+}
+
 pub(crate) fn assembly_script_get_owned_addresses_raw(env: &Env) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_owned_addrs())?;
+    sub_remaining_gas(env, env.gas_costs.get_owned_addrs)?;
     let data = match env.interface.get_owned_addresses() {
         Ok(data) => data,
         Err(err) => abi_bail!(err),
@@ -395,7 +842,7 @@ pub(crate) fn assembly_script_get_owned_addresses_raw(env: &Env) -> ABIResult<i3
 }
 
 pub(crate) fn assembly_script_get_call_stack_raw(env: &Env) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_call_stack())?;
+    sub_remaining_gas(env, env.gas_costs.get_call_stack)?;
     let data = match env.interface.get_call_stack() {
         Ok(data) => data,
         Err(err) => abi_bail!(err),
@@ -407,7 +854,7 @@ pub(crate) fn assembly_script_get_call_stack_raw(env: &Env) -> ABIResult<i32> {
 }
 
 pub(crate) fn assembly_script_get_owned_addresses(env: &Env) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_owned_addrs())?;
+    sub_remaining_gas(env, env.gas_costs.get_owned_addrs)?;
     match env.interface.get_owned_addresses() {
         Ok(data) => alloc_string_array(env, &data),
         Err(err) => abi_bail!(err),
@@ -415,7 +862,7 @@ pub(crate) fn assembly_script_get_owned_addresses(env: &Env) -> ABIResult<i32> {
 }
 
 pub(crate) fn assembly_script_get_call_stack(env: &Env) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_call_stack())?;
+    sub_remaining_gas(env, env.gas_costs.get_call_stack)?;
     match env.interface.get_call_stack() {
         Ok(data) => alloc_string_array(env, &data),
         Err(err) => abi_bail!(err),
@@ -423,9 +870,56 @@ pub(crate) fn assembly_script_get_call_stack(env: &Env) -> ABIResult<i32> {
 }
 
 pub(crate) fn assembly_script_generate_event(env: &Env, event: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_generate_event())?;
+    check_writable(env, "assembly_script_generate_event")?;
+    sub_remaining_gas(env, env.gas_costs.generate_event)?;
     let memory = get_memory!(env);
     let event = get_string(memory, event)?;
+    generate_event(env, Vec::new(), event.into_bytes())
+}
+
+/// Emit an event with topics, a JSON array of at most `MAX_EVENT_TOPICS`
+/// strings, and an `ArrayBuffer` payload
+pub(crate) fn assembly_script_generate_event_with_topics(
+    env: &Env,
+    topics: i32,
+    data: i32,
+) -> ABIResult<()> {
+    check_writable(env, "assembly_script_generate_event_with_topics")?;
+    sub_remaining_gas(env, env.gas_costs.generate_event)?;
+    let memory = get_memory!(env);
+    let topics = read_string_and_sub_gas(env, memory, topics, env.gas_costs.generate_event_mult)?;
+    let topics: Vec<String> = match serde_json::from_str(&topics) {
+        Ok(topics) => topics,
+        Err(err) => abi_bail!(format!("Invalid event topics: {}", err)),
+    };
+    if topics.len() > settings::MAX_EVENT_TOPICS {
+        abi_bail!(format!(
+            "Too many event topics: {} (max {})",
+            topics.len(),
+            settings::MAX_EVENT_TOPICS
+        ))
+    }
+    let data = read_buffer_and_sub_gas(env, memory, data, env.gas_costs.generate_event_mult)?;
+    generate_event(env, topics, data)
+}
+
+/// Emit an event from the module on top of the call stack
+fn generate_event(env: &Env, topics: Vec<String>, data: Vec<u8>) -> ABIResult<()> {
+    let call_stack = match env.interface.get_call_stack() {
+        Ok(call_stack) => call_stack,
+        Err(err) => abi_bail!(err),
+    };
+    let (call_stack_index, address) = match call_stack.last() {
+        Some(address) => (call_stack.len() - 1, address.clone()),
+        None => abi_bail!("Empty call stack, can't emit an event"),
+    };
+    let event = ScEvent {
+        address,
+        call_stack_index,
+        index: env.event_count.fetch_add(1, Ordering::Relaxed),
+        topics,
+        data,
+    };
     if let Err(err) = env.interface.generate_event(event) {
         abi_bail!(err)
     }
@@ -439,14 +933,10 @@ pub(crate) fn assembly_script_signature_verify(
     signature: i32,
     public_key: i32,
 ) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_signature_verify_const())?;
+    sub_remaining_gas(env, env.gas_costs.signature_verify_const)?;
     let memory = get_memory!(env);
-    let data = read_string_and_sub_gas(
-        env,
-        memory,
-        data,
-        settings::metering_signature_verify_data_mult(),
-    )?;
+    let data =
+        read_string_and_sub_gas(env, memory, data, env.gas_costs.signature_verify_data_mult)?;
     let signature = get_string(memory, signature)?;
     let public_key = get_string(memory, public_key)?;
     match env
@@ -464,7 +954,7 @@ pub(crate) fn assembly_script_address_from_public_key(
     env: &Env,
     public_key: i32,
 ) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_address_from_public_key())?;
+    sub_remaining_gas(env, env.gas_costs.address_from_public_key)?;
     let memory = get_memory!(env);
     let public_key = get_string(memory, public_key)?;
     match env.interface.address_from_public_key(&public_key) {
@@ -475,7 +965,7 @@ pub(crate) fn assembly_script_address_from_public_key(
 
 /// generates an unsafe random number
 pub(crate) fn assembly_script_unsafe_random(env: &Env) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_unsafe_random())?;
+    sub_remaining_gas(env, env.gas_costs.unsafe_random)?;
     match env.interface.unsafe_random() {
         Err(err) => abi_bail!(err),
         Ok(rnd) => Ok(rnd),
@@ -484,7 +974,7 @@ pub(crate) fn assembly_script_unsafe_random(env: &Env) -> ABIResult<i64> {
 
 /// gets the current unix timestamp in milliseconds
 pub(crate) fn assembly_script_get_time(env: &Env) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_get_time())?;
+    sub_remaining_gas(env, env.gas_costs.get_time)?;
     match env.interface.get_time() {
         Err(err) => abi_bail!(err),
         Ok(t) => Ok(t as i64),
@@ -506,7 +996,8 @@ pub(crate) fn assembly_script_send_message(
     raw_coins: i64,
     data: i32,
 ) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_send_message())?;
+    check_writable(env, "assembly_script_send_message")?;
+    sub_remaining_gas(env, env.gas_costs.send_message)?;
     let validity_start: (u64, u8) = match (
         validity_start_period.try_into(),
         validity_start_thread.try_into(),
@@ -550,7 +1041,7 @@ pub(crate) fn assembly_script_send_message(
 
 /// gets the period of the current execution slot
 pub(crate) fn assembly_script_get_current_period(env: &Env) -> ABIResult<i64> {
-    sub_remaining_gas(env, settings::metering_get_current_period())?;
+    sub_remaining_gas(env, env.gas_costs.get_current_period)?;
     match env.interface.get_current_period() {
         Err(err) => abi_bail!(err),
         Ok(v) => Ok(v as i64),
@@ -559,7 +1050,7 @@ pub(crate) fn assembly_script_get_current_period(env: &Env) -> ABIResult<i64> {
 
 /// gets the thread of the current execution slot
 pub(crate) fn assembly_script_get_current_thread(env: &Env) -> ABIResult<i32> {
-    sub_remaining_gas(env, settings::metering_get_current_thread())?;
+    sub_remaining_gas(env, env.gas_costs.get_current_thread)?;
     match env.interface.get_current_thread() {
         Err(err) => abi_bail!(err),
         Ok(v) => Ok(v as i32),
@@ -572,19 +1063,23 @@ pub(crate) fn assembly_script_set_bytecode_for(
     address: i32,
     bytecode_base64: i32,
 ) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_set_bytecode_const())?;
+    check_writable(env, "assembly_script_set_bytecode_for")?;
+    sub_remaining_gas(env, env.gas_costs.set_bytecode_const)?;
     let memory = get_memory!(env);
     let address = get_string(memory, address)?;
     let bytecode_base64 = read_string_and_sub_gas(
         env,
         memory,
         bytecode_base64,
-        settings::metering_set_bytecode_mult(),
+        env.gas_costs.set_bytecode_mult,
     )?;
     let bytecode_raw = match base64::decode(bytecode_base64) {
         Ok(v) => v,
         Err(err) => abi_bail!(err),
     };
+    if let Err(err) = validate_module(&bytecode_raw, &env.gas_costs) {
+        abi_bail!(err)
+    }
     match env.interface.raw_set_bytecode_for(&address, &bytecode_raw) {
         Ok(()) => Ok(()),
         Err(err) => abi_bail!(err),
@@ -593,18 +1088,22 @@ pub(crate) fn assembly_script_set_bytecode_for(
 
 /// sets the executable bytecode of the current address
 pub(crate) fn assembly_script_set_bytecode(env: &Env, bytecode_base64: i32) -> ABIResult<()> {
-    sub_remaining_gas(env, settings::metering_set_bytecode_const())?;
+    check_writable(env, "assembly_script_set_bytecode")?;
+    sub_remaining_gas(env, env.gas_costs.set_bytecode_const)?;
     let memory = get_memory!(env);
     let bytecode_base64 = read_string_and_sub_gas(
         env,
         memory,
         bytecode_base64,
-        settings::metering_set_bytecode_mult(),
+        env.gas_costs.set_bytecode_mult,
     )?;
     let bytecode_raw = match base64::decode(bytecode_base64) {
         Ok(v) => v,
         Err(err) => abi_bail!(err),
     };
+    if let Err(err) = validate_module(&bytecode_raw, &env.gas_costs) {
+        abi_bail!(err)
+    }
     match env.interface.raw_set_bytecode(&bytecode_raw) {
         Ok(()) => Ok(()),
         Err(err) => abi_bail!(err),
@@ -630,6 +1129,29 @@ fn pointer_from_utf8(env: &Env, value: &[u8]) -> ABIResult<StringPtr> {
     }
 }
 
+/// Tooling, return an ArrayBuffer allocated from bytes
+fn pointer_from_bytes(env: &Env, value: &[u8]) -> ABIResult<AnyPtr> {
+    match AnyPtr::alloc(&value.to_vec(), &env.wasm_env) {
+        Ok(ptr) => Ok(*ptr),
+        Err(err) => abi_bail!(err),
+    }
+}
+
+/// Tooling that read an ArrayBuffer in memory and substract remaining gas
+/// with a multiplicator (ArrayBuffer.byteLength * mult).
+///
+/// An AssemblyScript `Uint8Array` is passed with its `buffer`.
+fn read_buffer_and_sub_gas(
+    env: &Env,
+    memory: &Memory,
+    offset: i32,
+    mult: usize,
+) -> ABIResult<Vec<u8>> {
+    let value = read_buffer(memory, offset)?;
+    sub_remaining_gas_with_mult(env, value.len(), mult)?;
+    Ok(value)
+}
+
 /// Tooling that take read a String in memory and substract remaining gas
 /// with a multiplicator (String.len * mult).
 ///
@@ -652,6 +1174,48 @@ fn read_string_and_sub_gas(
     }
 }
 
+/// Write a pointer, a little endian `u32`, at the given offset
+fn write_pointer(memory: &Memory, offset: i32, pointer: i32) -> ABIResult<()> {
+    let view = memory.view::<u8>();
+    let offset = offset as u32 as usize;
+    match offset.checked_add(4).and_then(|end| view.get(offset..end)) {
+        Some(cells) => {
+            for (cell, byte) in cells.iter().zip(pointer.to_le_bytes()) {
+                cell.set(byte);
+            }
+            Ok(())
+        }
+        None => abi_bail!("Wrong offset: can't write pointer"),
+    }
+}
+
+/// Tooling, return the content of an ArrayBuffer from a given offset
+pub(crate) fn read_buffer(memory: &Memory, offset: i32) -> ABIResult<Vec<u8>> {
+    let view = memory.view::<u8>();
+    let offset = offset as u32 as usize;
+    // The size is stored in the 4 bytes before the object
+    // https://www.assemblyscript.org/memory.html#internals
+    let size = match offset
+        .checked_sub(4)
+        .and_then(|start| view.get(start..offset))
+    {
+        Some(cells) => u32::from_le_bytes([
+            cells[0].get(),
+            cells[1].get(),
+            cells[2].get(),
+            cells[3].get(),
+        ]) as usize,
+        None => abi_bail!("Wrong offset: can't read buffer size"),
+    };
+    match offset
+        .checked_add(size)
+        .and_then(|end| view.get(offset..end))
+    {
+        Some(cells) => Ok(cells.iter().map(|cell| cell.get()).collect()),
+        None => abi_bail!("Wrong offset: can't read buffer"),
+    }
+}
+
 /// Tooling, return a string from a given offset
 fn get_string(memory: &Memory, ptr: i32) -> ABIResult<String> {
     match StringPtr::new(ptr as u32).read(memory) {
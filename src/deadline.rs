@@ -0,0 +1,51 @@
+//! Wall-clock timeout of the executions
+//!
+//! The gas bounds the instructions executed, not the time spent in the host
+//! functions, for example when the `Interface` reads the disk. The `Deadline`
+//! of an execution started with `ExecutionOptions::timeout` is checked by the
+//! thread running it: by the host functions each time they charge gas, and by
+//! the webassembly code at the entry of the functions and of the loops, see
+//! `middlewares::CheckDeadline`. Once it's expired, the next check fails with
+//! a `TimeoutError`, turned into an `ExecutionError::Timeout`.
+
+use crate::abi_impl::ABIResult;
+use crate::env::Env;
+use crate::error::TimeoutError;
+use std::time::{Duration, Instant};
+use wasmer::RuntimeError;
+
+/// Deadline of an execution, shared with the modules it calls
+#[derive(Debug)]
+pub(crate) struct Deadline {
+    /// `None` if the timeout is too long to be represented
+    expires_at: Option<Instant>,
+    timeout: Duration,
+}
+
+impl Deadline {
+    pub fn new(timeout: Duration) -> Self {
+        Deadline {
+            expires_at: Instant::now().checked_add(timeout),
+            timeout,
+        }
+    }
+
+    /// Fail with a `TimeoutError` if the deadline expired
+    pub fn check(&self) -> ABIResult<()> {
+        if matches!(self.expires_at, Some(expires_at) if Instant::now() >= expires_at) {
+            return Err(RuntimeError::user(Box::new(TimeoutError {
+                timeout: self.timeout,
+            })));
+        }
+        Ok(())
+    }
+}
+
+/// Host function called by the webassembly code, see
+/// `middlewares::CheckDeadline`
+pub(crate) fn check_deadline(env: &Env) -> ABIResult<()> {
+    match &env.deadline {
+        Some(deadline) => deadline.check(),
+        None => Ok(()),
+    }
+}
@@ -1,26 +1,87 @@
 //! Extends the env of wasmer-as
 
 use crate::abi_impl::{abi_bail, get_memory, ABIResult};
-use crate::types::Interface;
+use crate::deadline::Deadline;
+use crate::error::AbortError;
+use crate::gas_report::GasReport;
+use crate::settings::{self, GasCosts};
+use crate::tracer::Tracer;
+use crate::types::{ExecutionOptions, Interface};
 use anyhow::Result;
 use as_ffi_bindings::{Read, StringPtr};
-use wasmer::{Global, HostEnvInitError, Instance, WasmerEnv};
+use std::sync::{atomic::AtomicU64, Arc, Mutex};
+use wasmer::{Global, HostEnvInitError, Instance, RuntimeError, WasmerEnv};
 
 #[derive(Clone)]
 pub struct Env {
     pub wasm_env: as_ffi_bindings::Env,
     pub interface: Box<dyn Interface>,
+    pub gas_costs: GasCosts,
+    pub context: CallContext,
+    /// Name of the host function the env is given to, see `for_host_function`
+    pub host_function: &'static str,
+    /// Report shared by the host functions of the execution, when requested
+    pub gas_report: Option<Arc<Mutex<GasReport>>>,
+    pub tracer: Option<Arc<dyn Tracer>>,
+    /// Number of events emitted by the execution, shared with the modules it
+    /// calls
+    pub event_count: Arc<AtomicU64>,
+    /// Deadline of the execution, see `ExecutionOptions::timeout`
+    pub deadline: Option<Arc<Deadline>>,
     pub remaining_points: Option<Global>,
     pub exhausted_points: Option<Global>,
+    pub stack_height: Option<Global>,
+}
+
+/// Context of an execution, given to the modules it calls
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CallContext {
+    /// Number of calls the execution is nested in, 0 for the top level
+    pub call_depth: u32,
+    /// The host functions changing the state fail
+    pub read_only: bool,
+    /// The gas used is reported, see `GasReport`
+    pub gas_report: bool,
+    /// Height of the stack of the caller, see `GasCosts::max_stack_height`
+    pub stack_height: u32,
+    /// Pages of memory used by the callers, see `GasCosts::max_number_of_pages`
+    pub memory_pages: u32,
+}
+
+impl CallContext {
+    /// Context of the top level execution
+    pub fn new(options: &ExecutionOptions) -> Self {
+        CallContext {
+            gas_report: options.gas_report,
+            ..Default::default()
+        }
+    }
 }
 
 impl Env {
-    pub fn new(interface: &dyn Interface) -> Env {
+    pub fn new(interface: &dyn Interface, gas_costs: GasCosts, context: CallContext) -> Env {
         Env {
             wasm_env: Default::default(),
             interface: interface.clone_box(),
+            gas_costs,
+            context,
+            host_function: "",
+            gas_report: context.gas_report.then(Default::default),
+            tracer: None,
+            event_count: Default::default(),
+            deadline: None,
             remaining_points: None,
             exhausted_points: None,
+            stack_height: None,
+        }
+    }
+
+    /// Clone of the env given to a host function, charging the gas to it in
+    /// the gas report
+    pub fn for_host_function(&self, host_function: &'static str) -> Env {
+        Env {
+            host_function,
+            ..self.clone()
         }
     }
 }
@@ -40,6 +101,12 @@ impl WasmerEnv for Env {
                 .get_with_generics_weak("wasmer_metering_points_exhausted")
                 .map_err(HostEnvInitError::from)?,
         );
+        self.stack_height = Some(
+            instance
+                .exports
+                .get_with_generics_weak(settings::STACK_HEIGHT)
+                .map_err(HostEnvInitError::from)?,
+        );
         Ok(())
     }
 }
@@ -65,6 +132,20 @@ pub fn get_remaining_points(env: &Env) -> ABIResult<u64> {
     }
 }
 
+/// Get the height of the stack, see `middlewares::StackHeight`
+pub fn get_stack_height(env: &Env) -> ABIResult<u32> {
+    match env.stack_height.as_ref() {
+        Some(stack_height) => match stack_height.get().try_into() {
+            Ok::<i64, _>(height) => match u32::try_from(height) {
+                Ok(height) => Ok(height),
+                Err(_) => abi_bail!("stack_height out of range"),
+            },
+            Err(_) => abi_bail!("stack_height has wrong type"),
+        },
+        None => abi_bail!("Lost reference to stack_height"),
+    }
+}
+
 /// Set remaining metering points
 /// Should be equivalent to
 /// https://github.com/wasmerio/wasmer/blob/8f2e49d52823cb7704d93683ce798aa84b6928c8/lib/middlewares/src/metering.rs#L343
@@ -88,13 +169,57 @@ pub fn set_remaining_points(env: &Env, points: u64) -> ABIResult<()> {
     Ok(())
 }
 
+/// Substract the constant cost of a host function, charged once by each call
 pub fn sub_remaining_gas(env: &Env, gas: u64) -> ABIResult<()> {
+    charge_gas(env, gas, true, 0)
+}
+
+/// Count a call of a host function that has no constant cost in the gas
+/// report
+pub fn count_host_call(env: &Env) {
+    if let Some(gas_report) = &env.gas_report {
+        gas_report
+            .lock()
+            .unwrap()
+            .charge_host_function(env.host_function, 0, true, 0);
+    }
+}
+
+/// Substract the gas charged by a host function, every charge going through
+/// it to be reported
+fn charge_gas(env: &Env, gas: u64, call: bool, bytes: u64) -> ABIResult<()> {
+    if let Some(deadline) = &env.deadline {
+        deadline.check()?;
+    }
     let remaining_gas = get_remaining_points(env)?;
     if let Some(remaining_gas) = remaining_gas.checked_sub(gas) {
         set_remaining_points(env, remaining_gas)?;
     } else {
+        set_points_exhausted(env)?;
         abi_bail!("Remaining gas reach zero")
     }
+    if let Some(gas_report) = &env.gas_report {
+        gas_report
+            .lock()
+            .unwrap()
+            .charge_host_function(env.host_function, gas, call, bytes);
+    }
+    Ok(())
+}
+
+/// Mark the metering points as exhausted, as the metering middleware does
+/// when an instruction costs more than the remaining points, so the error is
+/// reported as an out of gas.
+fn set_points_exhausted(env: &Env) -> ABIResult<()> {
+    set_remaining_points(env, 0)?;
+    match env.exhausted_points.as_ref() {
+        Some(exhausted_points) => {
+            if exhausted_points.set(1i32.into()).is_err() {
+                abi_bail!("Can't set exhausted_points")
+            }
+        }
+        None => abi_bail!("Lost reference to exhausted_points"),
+    };
     Ok(())
 }
 
@@ -102,20 +227,21 @@ pub fn sub_remaining_gas(env: &Env, gas: u64) -> ABIResult<()> {
 /// the result.
 pub fn sub_remaining_gas_with_mult(env: &Env, a: usize, b: usize) -> ABIResult<()> {
     match a.checked_mul(b) {
-        Some(gas) => sub_remaining_gas(env, gas as u64),
+        Some(gas) => charge_gas(env, gas as u64, false, a as u64),
         None => abi_bail!(format!("Multiplication overflow {} {}", a, b)),
     }
 }
 
-/// Called by the instance when an error popped. It print the filename where the error
-/// had pop up, an error message and more stacktrace information as line and column
+/// Called by the instance when an error popped, with the filename where the
+/// error had pop up, an error message and more stacktrace information as line
+/// and column. They are given to `Interface::on_abort`, then the execution
+/// stops with an `ExecutionError::Abort`.
 ///
 /// This function is automatically exported by AssemblyScript on build and allow assemblyscript
 /// to log what appened when a smartcontract crashed inside the instance.
 ///
 /// Because AssemblyScript require this to be imported:
 /// - To create an instance, this function has to be in the ImportObject in the "env" namespace.
-/// - We can take advantage of the behaviours printing the assemblyscript error
 pub fn assembly_script_abort(
     env: &Env,
     message: StringPtr,
@@ -124,17 +250,19 @@ pub fn assembly_script_abort(
     col: i32,
 ) -> ABIResult<()> {
     let memory = get_memory!(env);
-    let message = message.read(memory);
-    let filename = filename.read(memory);
-    if message.is_err() || filename.is_err() {
-        abi_bail!("Aborting failed to load message or filename")
+    let (message, filename) = match (message.read(memory), filename.read(memory)) {
+        (Ok(message), Ok(filename)) => (message, filename),
+        _ => abi_bail!("Aborting failed to load message or filename"),
+    };
+    if let Err(err) = env.interface.on_abort(&message, &filename, line, col) {
+        abi_bail!(err)
     }
-    eprintln!(
-        "Error: {} at {}:{} col: {}",
-        message.unwrap(),
-        filename.unwrap(),
+    let error = AbortError {
+        message,
+        filename,
         line,
-        col
-    );
-    Ok(())
+        col,
+    };
+    // Stop the execution, the error is turned into an `ExecutionError::Abort`
+    Err(RuntimeError::user(Box::new(error)))
 }
@@ -0,0 +1,262 @@
+//! Errors returned by `run_main` and `run_function`
+
+use std::fmt;
+use std::time::Duration;
+
+/// Reason why an execution failed
+///
+/// Every variant carries the name of the function executed when the error
+/// occurred and the gas that remained at that time. When a nested call fails,
+/// the error of the callee is returned as is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionError {
+    /// The gas limit has been reached
+    OutOfGas {
+        function: String,
+        remaining_gas: u64,
+    },
+    /// The AssemblyScript code called `abort`
+    Abort {
+        function: String,
+        remaining_gas: u64,
+        message: String,
+        filename: String,
+        line: i32,
+        col: i32,
+    },
+    /// A host function, most of the time through the `Interface`, failed
+    Interface {
+        function: String,
+        remaining_gas: u64,
+        error: String,
+    },
+    /// The function isn't exported by the module
+    MissingExport {
+        function: String,
+        remaining_gas: u64,
+    },
+    /// The bytecode isn't a valid module for this runtime
+    InvalidBytecode {
+        function: String,
+        remaining_gas: u64,
+        error: String,
+    },
+    /// The memory required by the module exceeds the allowed number of pages
+    MemoryLimit {
+        function: String,
+        remaining_gas: u64,
+        error: String,
+    },
+    /// The webassembly code trapped
+    Trap {
+        function: String,
+        remaining_gas: u64,
+        error: String,
+    },
+    /// The call would exceed the maximum number of nested calls
+    CallDepth {
+        function: String,
+        remaining_gas: u64,
+        max_call_depth: u32,
+    },
+    /// The called module is already on the call stack and denies reentrancy
+    Reentrancy {
+        function: String,
+        remaining_gas: u64,
+        address: String,
+    },
+    /// A host function changing the state was called by a read-only
+    /// execution
+    ReadOnly {
+        function: String,
+        remaining_gas: u64,
+        host_function: String,
+    },
+    /// The execution didn't finish before `ExecutionOptions::timeout`
+    ///
+    /// Unlike the other errors, it depends on the speed of the host: it must
+    /// not be part of the consensus.
+    Timeout {
+        function: String,
+        remaining_gas: u64,
+        timeout: Duration,
+    },
+}
+
+impl ExecutionError {
+    /// Function executed when the error occurred
+    pub fn function(&self) -> &str {
+        match self {
+            ExecutionError::OutOfGas { function, .. }
+            | ExecutionError::Abort { function, .. }
+            | ExecutionError::Interface { function, .. }
+            | ExecutionError::MissingExport { function, .. }
+            | ExecutionError::InvalidBytecode { function, .. }
+            | ExecutionError::MemoryLimit { function, .. }
+            | ExecutionError::Trap { function, .. }
+            | ExecutionError::CallDepth { function, .. }
+            | ExecutionError::Reentrancy { function, .. }
+            | ExecutionError::ReadOnly { function, .. }
+            | ExecutionError::Timeout { function, .. } => function,
+        }
+    }
+
+    /// Code of the error returned to the caller by `assembly_script_try_call`,
+    /// 0 meaning that the call succeeded
+    pub fn code(&self) -> i32 {
+        match self {
+            ExecutionError::OutOfGas { .. } => 1,
+            ExecutionError::Abort { .. } => 2,
+            ExecutionError::Interface { .. } => 3,
+            ExecutionError::MissingExport { .. } => 4,
+            ExecutionError::InvalidBytecode { .. } => 5,
+            ExecutionError::MemoryLimit { .. } => 6,
+            ExecutionError::Trap { .. } => 7,
+            ExecutionError::CallDepth { .. } => 8,
+            ExecutionError::Reentrancy { .. } => 9,
+            ExecutionError::ReadOnly { .. } => 10,
+            ExecutionError::Timeout { .. } => 11,
+        }
+    }
+
+    /// Gas remaining when the error occurred
+    pub fn remaining_gas(&self) -> u64 {
+        match self {
+            ExecutionError::OutOfGas { remaining_gas, .. }
+            | ExecutionError::Abort { remaining_gas, .. }
+            | ExecutionError::Interface { remaining_gas, .. }
+            | ExecutionError::MissingExport { remaining_gas, .. }
+            | ExecutionError::InvalidBytecode { remaining_gas, .. }
+            | ExecutionError::MemoryLimit { remaining_gas, .. }
+            | ExecutionError::Trap { remaining_gas, .. }
+            | ExecutionError::CallDepth { remaining_gas, .. }
+            | ExecutionError::Reentrancy { remaining_gas, .. }
+            | ExecutionError::ReadOnly { remaining_gas, .. }
+            | ExecutionError::Timeout { remaining_gas, .. } => *remaining_gas,
+        }
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::OutOfGas { function, .. } => {
+                write!(f, "Not enough gas, limit reached at: {}", function)
+            }
+            ExecutionError::Abort {
+                function,
+                message,
+                filename,
+                line,
+                col,
+                ..
+            } => write!(
+                f,
+                "{} aborted: {} at {}:{} col: {}",
+                function, message, filename, line, col
+            ),
+            ExecutionError::Interface {
+                function, error, ..
+            } => write!(f, "Host function failed in {}: {}", function, error),
+            ExecutionError::MissingExport { function, .. } => {
+                write!(f, "Missing export {}", function)
+            }
+            ExecutionError::InvalidBytecode {
+                function, error, ..
+            } => write!(f, "Invalid bytecode for {}: {}", function, error),
+            ExecutionError::MemoryLimit {
+                function, error, ..
+            } => write!(f, "Memory limit exceeded for {}: {}", function, error),
+            ExecutionError::Trap {
+                function, error, ..
+            } => write!(f, "Trap in {}: {}", function, error),
+            ExecutionError::CallDepth {
+                function,
+                max_call_depth,
+                ..
+            } => write!(
+                f,
+                "Call of {} exceeds the maximum call depth {}",
+                function, max_call_depth
+            ),
+            ExecutionError::Reentrancy {
+                function, address, ..
+            } => write!(
+                f,
+                "Reentrant call of {} at {}, already on the call stack",
+                function, address
+            ),
+            ExecutionError::ReadOnly {
+                function,
+                host_function,
+                ..
+            } => write!(
+                f,
+                "{} called {} in a read-only execution",
+                function, host_function
+            ),
+            ExecutionError::Timeout {
+                function, timeout, ..
+            } => write!(f, "{} didn't finish within {:?}", function, timeout),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+/// Error raised by the `abort` host function, turned into
+/// `ExecutionError::Abort` when the call returns.
+#[derive(Debug)]
+pub(crate) struct AbortError {
+    pub message: String,
+    pub filename: String,
+    pub line: i32,
+    pub col: i32,
+}
+
+impl fmt::Display for AbortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Error: {} at {}:{} col: {}",
+            self.message, self.filename, self.line, self.col
+        )
+    }
+}
+
+impl std::error::Error for AbortError {}
+
+/// Error raised by the host functions changing the state in a read-only
+/// execution, turned into `ExecutionError::ReadOnly` when the call returns.
+#[derive(Debug)]
+pub(crate) struct ReadOnlyError {
+    pub host_function: String,
+}
+
+impl fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} can't be called in a read-only execution",
+            self.host_function
+        )
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
+
+/// Error raised when the deadline of the execution expired, see
+/// `deadline::Deadline`, turned into `ExecutionError::Timeout` when the call
+/// returns.
+#[derive(Debug)]
+pub(crate) struct TimeoutError {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the execution didn't finish within {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
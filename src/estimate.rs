@@ -0,0 +1,82 @@
+//! Gas estimation of a function, without changing the state
+
+use crate::error::ExecutionError;
+use crate::execution_impl::run_function;
+use crate::journal::JournaledInterface;
+use crate::settings::GasCosts;
+use crate::types::{ExecutionResult, Interface};
+
+/// Gas needed by a function, see `estimate_gas`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GasEstimate {
+    /// Gas used by the function when it's given the maximum gas
+    pub gas_used: u64,
+    /// Smallest gas limit under which the function succeeds
+    pub min_limit: u64,
+}
+
+/// Estimate the gas needed to run a function of a module, like `run_function`
+/// with at most `max_gas`
+///
+/// The function is run several times, the changes made through the interface
+/// being reverted after each run and its events and async messages dropped:
+/// see `JournaledInterface::dry_run`. The smallest
+/// limit can be greater than the gas used with `max_gas`, for example when the
+/// function calls other modules with a part of its remaining gas, so it's
+/// searched by dichotomy.
+///
+/// Like with `run_function`, `max_gas` bounds the runs and `gas_costs` is
+/// the schedule they are charged with, which must be the one of the real
+/// execution for the estimate to be right.
+///
+/// Returns the error of the function if it fails with `max_gas`.
+pub fn estimate_gas(
+    module: &[u8],
+    max_gas: u64,
+    function: &str,
+    param: &str,
+    interface: &dyn Interface,
+    gas_costs: GasCosts,
+) -> Result<GasEstimate, ExecutionError> {
+    let journaled = JournaledInterface::dry_run(interface.clone_box());
+    let dry_run = |limit: u64| -> Result<Result<ExecutionResult, ExecutionError>, ExecutionError> {
+        let result = run_function(
+            module,
+            limit,
+            function,
+            param,
+            &journaled,
+            gas_costs.clone(),
+        );
+        match journaled.rollback() {
+            Ok(_) => Ok(result),
+            Err(err) => Err(ExecutionError::Interface {
+                function: function.to_string(),
+                remaining_gas: 0,
+                error: format!("can't revert the dry run: {}", err),
+            }),
+        }
+    };
+    let gas_used = dry_run(max_gas)??.gas_used;
+    // Most of the time the gas used is enough
+    if dry_run(gas_used)?.is_ok() {
+        return Ok(GasEstimate {
+            gas_used,
+            min_limit: gas_used,
+        });
+    }
+    // The function succeeds with `max_gas` but not with the gas it used
+    let (mut low, mut high) = (gas_used + 1, max_gas);
+    while low < high {
+        let limit = low + (high - low) / 2;
+        if dry_run(limit)?.is_ok() {
+            high = limit;
+        } else {
+            low = limit + 1;
+        }
+    }
+    Ok(GasEstimate {
+        gas_used,
+        min_limit: high,
+    })
+}
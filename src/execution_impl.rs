@@ -1,26 +1,44 @@
-use crate::settings;
-use crate::types::{Interface, Response};
+use crate::gas_report::GasReport;
+use crate::settings::{self, GasCosts};
+use crate::tracer::host_function;
+use crate::types::{ExecutionOptions, ExecutionResult, Interface, ReturnValue};
 use crate::{abi_impl::*, tunable_memory::LimitingTunables};
 use crate::{
-    env::{assembly_script_abort, get_remaining_points, Env},
-    settings::max_number_of_pages,
+    deadline::{check_deadline, Deadline},
+    env::{assembly_script_abort, CallContext, Env},
+    error::{AbortError, ExecutionError, ReadOnlyError, TimeoutError},
+    middlewares::{
+        set_callers_memory_pages, set_stack_height, CheckDeadline, DeferredStart, FrameCosts,
+        MemoryGrow, RejectFloats, StackHeight,
+    },
+    module_cache::{self, CompilationConfig},
+    validation::check_reserved_names,
 };
 use anyhow::{bail, Result};
-use as_ffi_bindings::{Read as ASRead, StringPtr, Write as ASWrite};
+use as_ffi_bindings::{AnyPtr, Read as ASRead, StringPtr, Type, Write as ASWrite};
 use std::sync::Arc;
+use std::time::Duration;
 use wasmer::WasmerEnv;
 use wasmer::{
-    imports, CompilerConfig, Features, Function, ImportObject, Instance, Module, Store, Universal,
-    Val,
+    imports, CompilerConfig, Features, Function, ImportObject, Instance, InstantiationError,
+    LinkError, Memory, Module, RuntimeError, Store, Universal, Val,
 };
 use wasmer::{wasmparser::Operator, BaseTunables, Pages, Target};
 use wasmer_compiler_singlepass::Singlepass;
 use wasmer_middlewares::metering::{self, MeteringPoints};
 use wasmer_middlewares::Metering;
 
-/// Create an instance of VM from a module with a given interface, an operation
-/// number limit and a webassembly module
-fn create_instance(limit: u64, module: &[u8], env: &Env) -> Result<Instance> {
+/// Compile a webassembly module for the given configuration
+///
+/// The compiled module doesn't depend on the gas limit of an execution: the
+/// metering points are set when the module is instantiated, see
+/// `create_instance`. It can therefore be cached and reused.
+pub(crate) fn compile_module(bytecode: &[u8], config: &CompilationConfig) -> Result<Module> {
+    // The names of the exports and imports added by the middlewares must be
+    // free
+    let bytecode = wasmer::wat2wasm(bytecode)?;
+    check_reserved_names(&bytecode)?;
+
     // We use the Singlepass compiler because it is fast and adapted to blockchains
     // See https://docs.rs/wasmer-compiler-singlepass/latest/wasmer_compiler_singlepass/
     let mut compiler_config = Singlepass::new();
@@ -48,120 +66,380 @@ fn create_instance(limit: u64, module: &[u8], env: &Env) -> Result<Instance> {
     // Turn-off multivalue, because it is not supported for Singlepass(and it's true by default).
     features.multi_value(false);
 
-    // Add metering middleware
-    let metering = Arc::new(Metering::new(limit, |_: &Operator| -> u64 { 1 }));
+    // Add metering middleware, the initial limit is overwritten when the
+    // module is instantiated.
+    let operator_costs = config.operator_costs.clone();
+    let metering = Arc::new(Metering::new(0, move |operator: &Operator| -> u64 {
+        operator_costs.cost(operator)
+    }));
     compiler_config.push_middleware(metering);
 
-    let base = BaseTunables::for_target(&Target::default());
-    let tunables = LimitingTunables::new(base, Pages(max_number_of_pages()));
+    // Charge the pages allocated by `memory.grow`, from the metering points
+    compiler_config.push_middleware(Arc::new(MemoryGrow::new(
+        config.memory_page_cost,
+        config.max_number_of_pages,
+    )));
+
+    // Strict determinism mode, the result of a float instruction may depend
+    // on the CPU even with canonicalized NaN
+    if config.reject_floats {
+        compiler_config.push_middleware(Arc::new(RejectFloats));
+    }
+
+    // Trap deterministically on deep recursions, instead of relying on the
+    // size of the native stack. Added after the metering so that the
+    // instructions it adds aren't metered.
+    let frame_costs = FrameCosts::new(&bytecode)?;
+    compiler_config.push_middleware(Arc::new(StackHeight::new(
+        config.max_stack_height,
+        frame_costs,
+    )));
+
+    // Prevent the start function to run before the metering points are set
+    compiler_config.push_middleware(Arc::new(DeferredStart));
+
+    // Stop the webassembly code once the deadline of the execution expired.
+    // Added last, as it shifts the indices of the functions, and only for the
+    // executions with a timeout.
+    if config.check_deadline {
+        compiler_config.push_middleware(Arc::new(CheckDeadline::default()));
+    }
+
     let engine = Universal::new(compiler_config).features(features).engine();
-    let store = Store::new_with_tunables(&engine, tunables);
+    let store = Store::new_with_tunables(&engine, create_tunables(config));
+    Ok(Module::new(&store, &*bytecode)?)
+}
+
+/// Load a module serialized with `Module::serialize`
+///
+/// # Safety
+/// The serialized module contains native code that is executed as is, it
+/// must come from `compile_module` with the same configuration.
+pub(crate) unsafe fn deserialize_module(
+    serialized: &[u8],
+    config: &CompilationConfig,
+) -> Result<Module> {
+    // No compiler is needed to load an already compiled module
+    let engine = Universal::headless().engine();
+    let store = Store::new_with_tunables(&engine, create_tunables(config));
+    Ok(Module::deserialize(&store, serialized)?)
+}
+
+fn create_tunables(config: &CompilationConfig) -> LimitingTunables<BaseTunables> {
+    let base = BaseTunables::for_target(&Target::default());
+    LimitingTunables::new(base, Pages(config.max_number_of_pages))
+}
+
+/// Create an instance of VM from a module with a given interface, an operation
+/// number limit and a webassembly module
+fn create_instance(
+    limit: u64,
+    module: &[u8],
+    function: &str,
+    env: &Env,
+) -> Result<Instance, ExecutionError> {
+    let config = CompilationConfig {
+        check_deadline: env.deadline.is_some(),
+        ..CompilationConfig::new(&env.gas_costs)
+    };
+    let module = module_cache::get_or_compile(module, &config).map_err(|err| {
+        ExecutionError::InvalidBytecode {
+            function: function.to_string(),
+            remaining_gas: limit,
+            error: err.to_string(),
+        }
+    })?;
+    let store = module.store().clone();
     let resolver: ImportObject = imports! {
         "env" => {
             // Needed by wasm generated by AssemblyScript.
-            "abort" =>  Function::new_native_with_env(&store, env.clone(), assembly_script_abort),
+            "abort" => host_function!(&store, env, "abort", assembly_script_abort, (message: str, filename: str, line: i32, col: i32) -> ()),
         },
         "massa" => {
-            "assembly_script_print" => Function::new_native_with_env(&store, env.clone(), assembly_script_print),
-            "assembly_script_call" => Function::new_native_with_env(&store, env.clone(), assembly_script_call_module),
-            "assembly_script_get_remaining_gas" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_remaining_gas),
-            "assembly_script_create_sc" => Function::new_native_with_env(&store, env.clone(), assembly_script_create_sc),
-            "assembly_script_set_data" => Function::new_native_with_env(&store, env.clone(), assembly_script_set_data),
-            "assembly_script_set_data_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_set_data_for),
-            "assembly_script_get_data" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_data),
-            "assembly_script_get_data_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_data_for),
-            "assembly_script_delete_data" => Function::new_native_with_env(&store, env.clone(), assembly_script_delete_data),
-            "assembly_script_delete_data_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_delete_data_for),
-            "assembly_script_append_data" => Function::new_native_with_env(&store, env.clone(), assembly_script_append_data),
-            "assembly_script_append_data_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_append_data_for),
-            "assembly_script_has_data" => Function::new_native_with_env(&store, env.clone(), assembly_script_has_data),
-            "assembly_script_has_data_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_has_data_for),
-            "assembly_script_get_owned_addresses" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_owned_addresses),
-            "assembly_script_get_owned_addresses_raw" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_owned_addresses_raw),
-            "assembly_script_get_call_stack" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_call_stack),
-            "assembly_script_get_call_stack_raw" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_call_stack_raw),
-            "assembly_script_generate_event" => Function::new_native_with_env(&store, env.clone(), assembly_script_generate_event),
-            "assembly_script_transfer_coins" => Function::new_native_with_env(&store, env.clone(), assembly_script_transfer_coins),
-            "assembly_script_transfer_coins_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_transfer_coins_for),
-            "assembly_script_get_balance" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_balance),
-            "assembly_script_get_balance_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_balance_for),
-            "assembly_script_hash" => Function::new_native_with_env(&store, env.clone(), assembly_script_hash),
-            "assembly_script_signature_verify" => Function::new_native_with_env(&store, env.clone(), assembly_script_signature_verify),
-            "assembly_script_address_from_public_key" => Function::new_native_with_env(&store, env.clone(), assembly_script_address_from_public_key),
-            "assembly_script_unsafe_random" => Function::new_native_with_env(&store, env.clone(), assembly_script_unsafe_random),
-            "assembly_script_get_call_coins" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_call_coins),
-            "assembly_script_get_time" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_time),
-            "assembly_script_send_message" => Function::new_native_with_env(&store, env.clone(), assembly_script_send_message),
-            "assembly_script_get_current_period" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_current_period),
-            "assembly_script_get_current_thread" => Function::new_native_with_env(&store, env.clone(), assembly_script_get_current_thread),
-            "assembly_script_set_bytecode" => Function::new_native_with_env(&store, env.clone(), assembly_script_set_bytecode),
-            "assembly_script_set_bytecode_for" => Function::new_native_with_env(&store, env.clone(), assembly_script_set_bytecode_for),
+            "assembly_script_print" => host_function!(&store, env, "assembly_script_print", assembly_script_print, (arg: ptr) -> ()),
+            "assembly_script_call" => host_function!(&store, env, "assembly_script_call", assembly_script_call_module, (address: ptr, function: ptr, param: ptr, call_coins: i64) -> ptr),
+            "assembly_script_call_non_reentrant" => host_function!(&store, env, "assembly_script_call_non_reentrant", assembly_script_call_non_reentrant, (address: ptr, function: ptr, param: ptr, call_coins: i64) -> ptr),
+            "assembly_script_static_call" => host_function!(&store, env, "assembly_script_static_call", assembly_script_static_call, (address: ptr, function: ptr, param: ptr) -> ptr),
+            "assembly_script_call_with_gas" => host_function!(&store, env, "assembly_script_call_with_gas", assembly_script_call_with_gas, (address: ptr, function: ptr, param: ptr, call_coins: i64, max_gas: i64) -> ptr),
+            "assembly_script_try_call" => host_function!(&store, env, "assembly_script_try_call", assembly_script_try_call, (address: ptr, function: ptr, param: ptr, call_coins: i64, result: i32) -> i32),
+            "assembly_script_try_call_with_gas" => host_function!(&store, env, "assembly_script_try_call_with_gas", assembly_script_try_call_with_gas, (address: ptr, function: ptr, param: ptr, call_coins: i64, max_gas: i64, result: i32) -> i32),
+            "assembly_script_get_remaining_gas" => host_function!(&store, env, "assembly_script_get_remaining_gas", assembly_script_get_remaining_gas, () -> i64),
+            "assembly_script_create_sc" => host_function!(&store, env, "assembly_script_create_sc", assembly_script_create_sc, (bytecode: ptr) -> ptr),
+            "assembly_script_set_data" => host_function!(&store, env, "assembly_script_set_data", assembly_script_set_data, (key: ptr, value: ptr) -> ()),
+            "assembly_script_set_data_for" => host_function!(&store, env, "assembly_script_set_data_for", assembly_script_set_data_for, (address: ptr, key: ptr, value: ptr) -> ()),
+            "assembly_script_get_data" => host_function!(&store, env, "assembly_script_get_data", assembly_script_get_data, (key: ptr) -> ptr),
+            "assembly_script_get_data_for" => host_function!(&store, env, "assembly_script_get_data_for", assembly_script_get_data_for, (address: ptr, key: ptr) -> ptr),
+            "assembly_script_delete_data" => host_function!(&store, env, "assembly_script_delete_data", assembly_script_delete_data, (key: ptr) -> ()),
+            "assembly_script_delete_data_for" => host_function!(&store, env, "assembly_script_delete_data_for", assembly_script_delete_data_for, (address: ptr, key: ptr) -> ()),
+            "assembly_script_append_data" => host_function!(&store, env, "assembly_script_append_data", assembly_script_append_data, (key: ptr, value: ptr) -> ()),
+            "assembly_script_append_data_for" => host_function!(&store, env, "assembly_script_append_data_for", assembly_script_append_data_for, (address: ptr, key: ptr, value: ptr) -> ()),
+            "assembly_script_set_data_bytes" => host_function!(&store, env, "assembly_script_set_data_bytes", assembly_script_set_data_bytes, (key: ptr, value: ptr) -> ()),
+            "assembly_script_set_data_bytes_for" => host_function!(&store, env, "assembly_script_set_data_bytes_for", assembly_script_set_data_bytes_for, (address: ptr, key: ptr, value: ptr) -> ()),
+            "assembly_script_get_data_bytes" => host_function!(&store, env, "assembly_script_get_data_bytes", assembly_script_get_data_bytes, (key: ptr) -> ptr),
+            "assembly_script_get_data_bytes_for" => host_function!(&store, env, "assembly_script_get_data_bytes_for", assembly_script_get_data_bytes_for, (address: ptr, key: ptr) -> ptr),
+            "assembly_script_append_data_bytes" => host_function!(&store, env, "assembly_script_append_data_bytes", assembly_script_append_data_bytes, (key: ptr, value: ptr) -> ()),
+            "assembly_script_append_data_bytes_for" => host_function!(&store, env, "assembly_script_append_data_bytes_for", assembly_script_append_data_bytes_for, (address: ptr, key: ptr, value: ptr) -> ()),
+            "assembly_script_has_data" => host_function!(&store, env, "assembly_script_has_data", assembly_script_has_data, (key: ptr) -> i32),
+            "assembly_script_has_data_for" => host_function!(&store, env, "assembly_script_has_data_for", assembly_script_has_data_for, (address: ptr, key: ptr) -> i32),
+            "assembly_script_get_keys" => host_function!(&store, env, "assembly_script_get_keys", assembly_script_get_keys, (prefix: ptr, offset: i32, count: i32) -> ptr),
+            "assembly_script_get_keys_for" => host_function!(&store, env, "assembly_script_get_keys_for", assembly_script_get_keys_for, (address: ptr, prefix: ptr, offset: i32, count: i32) -> ptr),
+            "assembly_script_get_owned_addresses" => host_function!(&store, env, "assembly_script_get_owned_addresses", assembly_script_get_owned_addresses, () -> ptr),
+            "assembly_script_get_owned_addresses_raw" => host_function!(&store, env, "assembly_script_get_owned_addresses_raw", assembly_script_get_owned_addresses_raw, () -> ptr),
+            "assembly_script_get_call_stack" => host_function!(&store, env, "assembly_script_get_call_stack", assembly_script_get_call_stack, () -> ptr),
+            "assembly_script_get_call_stack_raw" => host_function!(&store, env, "assembly_script_get_call_stack_raw", assembly_script_get_call_stack_raw, () -> ptr),
+            "assembly_script_generate_event" => host_function!(&store, env, "assembly_script_generate_event", assembly_script_generate_event, (event: ptr) -> ()),
+            "assembly_script_generate_event_with_topics" => host_function!(&store, env, "assembly_script_generate_event_with_topics", assembly_script_generate_event_with_topics, (topics: ptr, data: ptr) -> ()),
+            "assembly_script_transfer_coins" => host_function!(&store, env, "assembly_script_transfer_coins", assembly_script_transfer_coins, (to_address: ptr, raw_amount: i64) -> ()),
+            "assembly_script_transfer_coins_for" => host_function!(&store, env, "assembly_script_transfer_coins_for", assembly_script_transfer_coins_for, (from_address: ptr, to_address: ptr, raw_amount: i64) -> ()),
+            "assembly_script_get_balance" => host_function!(&store, env, "assembly_script_get_balance", assembly_script_get_balance, () -> i64),
+            "assembly_script_get_balance_for" => host_function!(&store, env, "assembly_script_get_balance_for", assembly_script_get_balance_for, (address: ptr) -> i64),
+            "assembly_script_hash" => host_function!(&store, env, "assembly_script_hash", assembly_script_hash, (value: ptr) -> ptr),
+            "assembly_script_signature_verify" => host_function!(&store, env, "assembly_script_signature_verify", assembly_script_signature_verify, (data: ptr, signature: ptr, public_key: ptr) -> i32),
+            "assembly_script_address_from_public_key" => host_function!(&store, env, "assembly_script_address_from_public_key", assembly_script_address_from_public_key, (public_key: ptr) -> ptr),
+            "assembly_script_unsafe_random" => host_function!(&store, env, "assembly_script_unsafe_random", assembly_script_unsafe_random, () -> i64),
+            "assembly_script_get_call_coins" => host_function!(&store, env, "assembly_script_get_call_coins", assembly_script_get_call_coins, () -> i64),
+            "assembly_script_get_time" => host_function!(&store, env, "assembly_script_get_time", assembly_script_get_time, () -> i64),
+            "assembly_script_send_message" => host_function!(&store, env, "assembly_script_send_message", assembly_script_send_message, (target_address: ptr, target_handler: ptr, validity_start_period: i64, validity_start_thread: i32, validity_end_period: i64, validity_end_thread: i32, max_gas: i64, gas_price: i64, raw_coins: i64, data: ptr) -> ()),
+            "assembly_script_get_current_period" => host_function!(&store, env, "assembly_script_get_current_period", assembly_script_get_current_period, () -> i64),
+            "assembly_script_get_current_thread" => host_function!(&store, env, "assembly_script_get_current_thread", assembly_script_get_current_thread, () -> i32),
+            "assembly_script_set_bytecode" => host_function!(&store, env, "assembly_script_set_bytecode", assembly_script_set_bytecode, (bytecode_base64: ptr) -> ()),
+            "assembly_script_set_bytecode_for" => host_function!(&store, env, "assembly_script_set_bytecode_for", assembly_script_set_bytecode_for, (address: ptr, bytecode_base64: ptr) -> ()),
+            // Imported by the `CheckDeadline` middleware of the executions with a
+            // timeout, neither traced nor charged
+            settings::CHECK_DEADLINE => Function::new_native_with_env(&store, env.clone(), check_deadline),
+        },
+    };
+    // The initial pages of memory are charged before they're allocated, and
+    // count in the pages used by the call stack
+    let pages: u32 = module.info().memories.values().map(|ty| ty.minimum.0).sum();
+    let fits = match env.context.memory_pages.checked_add(pages) {
+        Some(total_pages) => total_pages <= env.gas_costs.max_number_of_pages,
+        None => false,
+    };
+    if !fits {
+        return Err(ExecutionError::MemoryLimit {
+            function: function.to_string(),
+            remaining_gas: limit,
+            error: format!(
+                "{} initial pages of memory, {} are used by the callers and the maximum is {}",
+                pages, env.context.memory_pages, env.gas_costs.max_number_of_pages
+            ),
+        });
+    }
+    // An overflowing cost is more than any limit
+    let limit = match (pages as u64)
+        .checked_mul(env.gas_costs.memory_page)
+        .and_then(|cost| limit.checked_sub(cost))
+    {
+        Some(limit) => limit,
+        None => {
+            return Err(ExecutionError::OutOfGas {
+                function: function.to_string(),
+                remaining_gas: 0,
+            })
+        }
+    };
+    let instance = Instance::new(&module, &resolver)
+        .map_err(|err| instantiation_error(err, function, limit))?;
+    metering::set_remaining_points(&instance, limit);
+    set_stack_height(&instance, env.context.stack_height);
+    set_callers_memory_pages(&instance, env.context.memory_pages);
+    if let Ok(start) = instance.exports.get_function(settings::DEFERRED_START) {
+        if let Err(err) = start.call(&[]) {
+            return Err(call_error(err, function, &instance));
+        }
+    }
+    Ok(instance)
+}
+
+fn instantiation_error(error: InstantiationError, function: &str, limit: u64) -> ExecutionError {
+    let function = function.to_string();
+    match error {
+        // The tunables refused to create the memory
+        InstantiationError::Link(LinkError::Resource(error)) => ExecutionError::MemoryLimit {
+            function,
+            remaining_gas: limit,
+            error,
         },
+        error => ExecutionError::InvalidBytecode {
+            function,
+            remaining_gas: limit,
+            error: error.to_string(),
+        },
+    }
+}
+
+/// Turn the error returned by a call to the instance into an `ExecutionError`
+fn call_error(error: RuntimeError, function: &str, instance: &Instance) -> ExecutionError {
+    // The error of a nested call is forwarded as it is
+    let error = match error.downcast::<ExecutionError>() {
+        Ok(error) => return error,
+        Err(error) => error,
+    };
+    let function = function.to_string();
+    // Because the last needed more than the remaining points, we should have an error.
+    let remaining_gas = match metering::get_remaining_points(instance) {
+        MeteringPoints::Remaining(remaining_gas) => remaining_gas,
+        MeteringPoints::Exhausted => {
+            return ExecutionError::OutOfGas {
+                function,
+                remaining_gas: 0,
+            }
+        }
+    };
+    let error = match error.downcast::<AbortError>() {
+        Ok(AbortError {
+            message,
+            filename,
+            line,
+            col,
+        }) => {
+            return ExecutionError::Abort {
+                function,
+                remaining_gas,
+                message,
+                filename,
+                line,
+                col,
+            }
+        }
+        Err(error) => error,
+    };
+    let error = match error.downcast::<ReadOnlyError>() {
+        Ok(ReadOnlyError { host_function }) => {
+            return ExecutionError::ReadOnly {
+                function,
+                remaining_gas,
+                host_function,
+            }
+        }
+        Err(error) => error,
     };
-    let module = Module::new(&store, &module)?;
-    Ok(Instance::new(&module, &resolver)?)
+    match error.downcast::<TimeoutError>() {
+        Ok(TimeoutError { timeout }) => ExecutionError::Timeout {
+            function,
+            remaining_gas,
+            timeout,
+        },
+        // Host functions fail with generic errors, without trap code
+        Err(error) => match error.clone().to_trap() {
+            Some(_) => ExecutionError::Trap {
+                function,
+                remaining_gas,
+                error: error.message(),
+            },
+            None => ExecutionError::Interface {
+                function,
+                remaining_gas,
+                error: error.message(),
+            },
+        },
+    }
+}
+
+fn remaining_gas(instance: &Instance) -> u64 {
+    match metering::get_remaining_points(instance) {
+        MeteringPoints::Remaining(remaining_gas) => remaining_gas,
+        MeteringPoints::Exhausted => 0,
+    }
 }
 
+/// Execute a function of the module, with the env given to the host functions
+/// of the instance when it's already created
 pub(crate) fn exec(
     limit: u64,
     instance: Option<Instance>,
     module: &[u8],
     function: &str,
     param: &str,
-    interface: &dyn Interface,
-) -> Result<Response> {
-    let mut env = Env::new(interface);
+    mut env: Env,
+) -> Result<ExecutionResult, ExecutionError> {
     let instance = match instance {
         Some(instance) => instance,
-        None => create_instance(limit, module, &env)?,
+        None => create_instance(limit, module, function, &env)?,
     };
-    env.init_with_instance(&instance)?;
-
-    // Closure for the execution allowing us to handle a gas error
-    fn execution(instance: &Instance, function: &str, param: &str, env: &Env) -> Result<Response> {
-        let param_ptr = *StringPtr::alloc(&param.to_string(), &env.wasm_env)?;
-        match instance
-            .exports
-            .get_function(function)?
-            .call(&[Val::I32(param_ptr.offset() as i32)])
-        {
-            Ok(value) => {
-                // TODO: clean and define wat should be return by the main
-                if function.eq(crate::settings::MAIN) {
-                    return Ok(Response {
-                        ret: "0".to_string(),
-                        remaining_gas: get_remaining_points(env)?,
-                    });
-                }
-                let ret = if let Some(offset) = value.get(0) {
-                    if let Some(offset) = offset.i32() {
-                        let str_ptr = StringPtr::new(offset as u32);
-                        let memory = instance.exports.get_memory("memory")?;
-                        str_ptr.read(memory)?
-                    } else {
-                        bail!("Execution wasn't in capacity to read the return value")
-                    }
-                } else {
-                    String::new()
-                };
-                Ok(Response {
-                    ret,
-                    remaining_gas: get_remaining_points(env)?,
-                })
-            }
-            Err(error) => bail!(error),
+    let invalid_bytecode = |error: String| ExecutionError::InvalidBytecode {
+        function: function.to_string(),
+        remaining_gas: remaining_gas(&instance),
+        error,
+    };
+    env.init_with_instance(&instance)
+        .map_err(|err| invalid_bytecode(err.to_string()))?;
+    let exported_function = match instance.exports.get_function(function) {
+        Ok(exported_function) => exported_function,
+        Err(_) => {
+            return Err(ExecutionError::MissingExport {
+                function: function.to_string(),
+                remaining_gas: remaining_gas(&instance),
+            })
         }
-    }
-
-    match execution(&instance, function, param, &env) {
-        Ok(response) => Ok(response),
+    };
+    let param_ptr = match StringPtr::alloc(&param.to_string(), &env.wasm_env) {
+        Ok(param_ptr) => *param_ptr,
         Err(err) => {
-            // Because the last needed more than the remaining points, we should have an error.
-            match metering::get_remaining_points(&instance) {
-                MeteringPoints::Remaining(..) => bail!(err),
-                MeteringPoints::Exhausted => bail!("Not enough gas, limit reached at: {function}"),
-            }
+            return Err(match err.downcast::<RuntimeError>() {
+                Ok(err) => call_error(err, function, &instance),
+                Err(err) => invalid_bytecode(err.to_string()),
+            })
+        }
+    };
+    let value = exported_function
+        .call(&[Val::I32(param_ptr.offset() as i32)])
+        .map_err(|err| call_error(err, function, &instance))?;
+    let ret = match value.first() {
+        None => ReturnValue::Empty,
+        // `main` returns an exit code
+        Some(Val::I32(code)) if function == settings::MAIN => ReturnValue::Int(*code as i64),
+        Some(Val::I32(offset)) => {
+            let memory = instance
+                .exports
+                .get_memory("memory")
+                .map_err(|err| invalid_bytecode(err.to_string()))?;
+            read_return_value(memory, *offset).map_err(|err| invalid_bytecode(err.to_string()))?
         }
+        Some(Val::I64(value)) => ReturnValue::Int(*value),
+        Some(_) => {
+            return Err(invalid_bytecode(
+                "Execution wasn't in capacity to read the return value".to_string(),
+            ))
+        }
+    };
+    let remaining_gas = remaining_gas(&instance);
+    let gas_used = limit.saturating_sub(remaining_gas);
+    Ok(ExecutionResult {
+        ret,
+        gas_used,
+        remaining_gas,
+        gas_report: finish_gas_report(&env, &instance, function, gas_used),
+    })
+}
+
+fn finish_gas_report(
+    env: &Env,
+    instance: &Instance,
+    function: &str,
+    gas_used: u64,
+) -> Option<GasReport> {
+    env.gas_report.as_ref().map(|gas_report| {
+        // Every page of the memory has been charged, the initial ones by
+        // `create_instance` and the others by `memory.grow`
+        let pages = instance
+            .exports
+            .get_memory("memory")
+            .map_or(0, |memory| memory.size().0);
+        let memory_pages = (pages as u64).saturating_mul(env.gas_costs.memory_page);
+        let mut gas_report = gas_report.lock().unwrap();
+        gas_report.finish(function, gas_used, memory_pages);
+        gas_report.clone()
+    })
+}
+
+/// Read the object returned by a function according to its AssemblyScript type
+pub(crate) fn read_return_value(memory: &Memory, offset: i32) -> Result<ReturnValue> {
+    match AnyPtr::new(offset as u32).to_type(memory)? {
+        Type::String(ptr) => Ok(ReturnValue::String(ptr.read(memory)?)),
+        Type::Buffer(_) => Ok(ReturnValue::Bytes(read_buffer(memory, offset)?)),
+        Type::Any(_) => bail!("Execution wasn't in capacity to read the return value"),
     }
 }
 
@@ -176,14 +454,41 @@ pub(crate) fn exec(
 ///     return 0;
 /// }
 /// ```  
-pub fn run_main(module: &[u8], limit: u64, interface: &dyn Interface) -> Result<u64> {
-    let env = Env::new(interface);
-    let instance = create_instance(limit, module, &env)?;
-    if instance.exports.contains(settings::MAIN) {
-        Ok(exec(limit, Some(instance), module, settings::MAIN, "", interface)?.remaining_gas)
-    } else {
-        Ok(limit)
-    }
+pub fn run_main(
+    module: &[u8],
+    limit: u64,
+    interface: &dyn Interface,
+    gas_costs: GasCosts,
+) -> Result<ExecutionResult, ExecutionError> {
+    run_main_with_options(module, limit, interface, gas_costs, Default::default())
+}
+
+/// Same as `run_main`, with the given options
+pub fn run_main_with_options(
+    module: &[u8],
+    limit: u64,
+    interface: &dyn Interface,
+    gas_costs: GasCosts,
+    options: ExecutionOptions,
+) -> Result<ExecutionResult, ExecutionError> {
+    let mut env = Env::new(interface, gas_costs, CallContext::new(&options));
+    env.tracer = options.tracer;
+    with_timeout(options.timeout, env, |env| {
+        let instance = create_instance(limit, module, settings::MAIN, &env)?;
+        if instance.exports.contains(settings::MAIN) {
+            exec(limit, Some(instance), module, settings::MAIN, "", env)
+        } else {
+            // Only the start function has been executed
+            let remaining_gas = remaining_gas(&instance);
+            let gas_used = limit.saturating_sub(remaining_gas);
+            Ok(ExecutionResult {
+                ret: ReturnValue::Empty,
+                gas_used,
+                remaining_gas,
+                gas_report: finish_gas_report(&env, &instance, settings::MAIN, gas_used),
+            })
+        }
+    })
 }
 
 /// Library Input, take a `module` wasm builded with the massa environment,
@@ -203,6 +508,80 @@ pub fn run_function(
     function: &str,
     param: &str,
     interface: &dyn Interface,
-) -> Result<u64> {
-    Ok(exec(limit, None, module, function, param, interface)?.remaining_gas)
+    gas_costs: GasCosts,
+) -> Result<ExecutionResult, ExecutionError> {
+    let options = Default::default();
+    run_function_with_options(
+        module, limit, function, param, interface, gas_costs, options,
+    )
+}
+
+/// Same as `run_function`, with the given options
+pub fn run_function_with_options(
+    module: &[u8],
+    limit: u64,
+    function: &str,
+    param: &str,
+    interface: &dyn Interface,
+    gas_costs: GasCosts,
+    options: ExecutionOptions,
+) -> Result<ExecutionResult, ExecutionError> {
+    let mut env = Env::new(interface, gas_costs, CallContext::new(&options));
+    env.tracer = options.tracer;
+    with_timeout(options.timeout, env, |env| {
+        exec(limit, None, module, function, param, env)
+    })
+}
+
+/// Run an execution, failing with `ExecutionError::Timeout` if it doesn't
+/// finish within the timeout, see `Deadline`
+fn with_timeout(
+    timeout: Option<Duration>,
+    mut env: Env,
+    run: impl FnOnce(Env) -> Result<ExecutionResult, ExecutionError>,
+) -> Result<ExecutionResult, ExecutionError> {
+    env.deadline = timeout.map(|timeout| Arc::new(Deadline::new(timeout)));
+    run(env)
+}
+
+/// Library Input, take a `module` wasm builded with the massa environment,
+/// run a function of that module with the given parameter, like
+/// `run_function`, without being able to change the state
+///
+/// The host functions changing the state (datastore, coins, bytecode,
+/// messages and events) fail with `ExecutionError::ReadOnly`, as well as in
+/// the modules called by the function.
+pub fn run_function_readonly(
+    module: &[u8],
+    limit: u64,
+    function: &str,
+    param: &str,
+    interface: &dyn Interface,
+    gas_costs: GasCosts,
+) -> Result<ExecutionResult, ExecutionError> {
+    let options = Default::default();
+    run_function_readonly_with_options(
+        module, limit, function, param, interface, gas_costs, options,
+    )
+}
+
+/// Same as `run_function_readonly`, with the given options
+pub fn run_function_readonly_with_options(
+    module: &[u8],
+    limit: u64,
+    function: &str,
+    param: &str,
+    interface: &dyn Interface,
+    gas_costs: GasCosts,
+    options: ExecutionOptions,
+) -> Result<ExecutionResult, ExecutionError> {
+    let context = CallContext {
+        read_only: true,
+        ..CallContext::new(&options)
+    };
+    let mut env = Env::new(interface, gas_costs, context);
+    env.tracer = options.tracer;
+    with_timeout(options.timeout, env, |env| {
+        exec(limit, None, module, function, param, env)
+    })
 }
@@ -0,0 +1,71 @@
+//! Breakdown of the gas used by an execution
+//!
+//! Requested with `ExecutionOptions::gas_report`, the report splits the gas
+//! used by a function between the webassembly instructions, the pages of
+//! memory, each host function and each module it called.
+
+use std::collections::BTreeMap;
+
+/// Gas used by a host function during an execution
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostFunctionGas {
+    /// Number of calls
+    pub calls: u64,
+    /// Gas charged by the calls
+    pub gas: u64,
+    /// Number of bytes (or of items, like the keys of the datastore) charged
+    /// per unit
+    pub bytes: u64,
+}
+
+/// Gas used by the function of a module
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GasReport {
+    /// Address of the module, `None` for the executed module
+    pub address: Option<String>,
+    pub function: String,
+    /// Total gas used by the function, including the host functions and the
+    /// nested calls
+    pub gas_used: u64,
+    /// Gas used by the webassembly instructions
+    pub instructions: u64,
+    /// Gas used by the pages of memory of the module: the initial pages and
+    /// the pages allocated by `memory.grow`, see `GasCosts::memory_page`
+    pub memory_pages: u64,
+    /// Gas used by each host function, by name
+    pub host_functions: BTreeMap<String, HostFunctionGas>,
+    /// Reports of the modules called by the function, in call order
+    ///
+    /// The report of a call that failed only gives the gas it used.
+    pub calls: Vec<GasReport>,
+}
+
+impl GasReport {
+    pub(crate) fn charge_host_function(
+        &mut self,
+        host_function: &str,
+        gas: u64,
+        call: bool,
+        bytes: u64,
+    ) {
+        let entry = self
+            .host_functions
+            .entry(host_function.to_string())
+            .or_default();
+        entry.calls += call as u64;
+        entry.gas += gas;
+        entry.bytes += bytes;
+    }
+
+    /// Set the total gas used and the gas used by the pages of memory, the
+    /// instructions getting what isn't used by the pages of memory, the host
+    /// functions or the nested calls
+    pub(crate) fn finish(&mut self, function: &str, gas_used: u64, memory_pages: u64) {
+        let host_functions: u64 = self.host_functions.values().map(|entry| entry.gas).sum();
+        let calls: u64 = self.calls.iter().map(|call| call.gas_used).sum();
+        self.function = function.to_string();
+        self.gas_used = gas_used;
+        self.memory_pages = memory_pages;
+        self.instructions = gas_used.saturating_sub(memory_pages + host_functions + calls);
+    }
+}
@@ -0,0 +1,89 @@
+//! Gas schedules loaded from a file
+//!
+//! The node describes the costs of each network version in a JSON or TOML
+//! file, for example:
+//!
+//! ```toml
+//! version = 1
+//!
+//! [costs]
+//! call = 200
+//! set_data_const = 100
+//! # ... every field of `GasCosts`
+//! max_number_of_pages = 64
+//!
+//! [costs.validation]
+//! max_functions = 8192
+//! # ... every field of `ValidationLimits`
+//!
+//! [costs.operators]
+//! default = 1
+//! # ... every field of `OperatorCosts`
+//! ```
+//!
+//! Every field of `GasCosts` must be given: a missing or an unknown field is
+//! an error, so a schedule never silently falls back on the default costs.
+
+use crate::settings::GasCosts;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Maximum number of pages of a webassembly memory
+const WASM_MAX_PAGES: u32 = 65_536;
+
+/// Versioned gas schedule
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GasSchedule {
+    /// Version of the schedule, chosen by the node
+    pub version: u32,
+    /// Costs applied by the executions using this schedule
+    pub costs: GasCosts,
+}
+
+impl GasSchedule {
+    /// Parse and validate a schedule written in JSON
+    pub fn from_json(json: &str) -> Result<Self> {
+        let schedule: GasSchedule =
+            serde_json::from_str(json).context("invalid JSON gas schedule")?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    /// Parse and validate a schedule written in TOML
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let schedule: GasSchedule = toml::from_str(toml).context("invalid TOML gas schedule")?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    /// Load a schedule from a `.json` or a `.toml` file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read gas schedule {}", path.display()))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => GasSchedule::from_json(&content),
+            Some("toml") => GasSchedule::from_toml(&content),
+            _ => bail!(
+                "unknown gas schedule format {}, expected a .json or a .toml file",
+                path.display()
+            ),
+        }
+    }
+
+    /// Check the values that can't be expressed by the types of the fields
+    pub fn validate(&self) -> Result<()> {
+        let pages = self.costs.max_number_of_pages;
+        if pages == 0 || pages > WASM_MAX_PAGES {
+            bail!(
+                "gas schedule {}: max_number_of_pages must be between 1 and {}, got {}",
+                self.version,
+                WASM_MAX_PAGES,
+                pages
+            );
+        }
+        Ok(())
+    }
+}
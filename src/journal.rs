@@ -0,0 +1,433 @@
+//! Journaled interface, reverting the changes of the failed calls
+//!
+//! `JournaledInterface` wraps the `Interface` of the node and records how to
+//! undo each change made through it. Each `init_call` opens a checkpoint in
+//! the journal: if the call succeeds, `finish_call` keeps its changes, which
+//! now belong to the caller; if it fails, `revert_call` undoes them, from the
+//! last to the first.
+//!
+//! The changes are undone with the `_for` functions of the wrapped interface,
+//! the addresses being read with `get_call_stack` when a change is recorded.
+//!
+//! The events and the async messages can't be undone: they are given to the
+//! wrapped interface, except in a dry run, see `JournaledInterface::dry_run`.
+
+use crate::types::{Interface, InterfaceClone, ReentrancyPolicy, ScEvent};
+use anyhow::{bail, Result};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// How to undo a change
+enum UndoEntry {
+    /// Restore the previous value of a datastore entry, or delete it
+    Data {
+        address: String,
+        key: String,
+        previous: Option<Vec<u8>>,
+    },
+    /// Transfer the coins back
+    Transfer {
+        from_address: String,
+        to_address: String,
+        raw_amount: u64,
+    },
+    /// Restore the previous bytecode of an address
+    Bytecode { address: String, previous: Vec<u8> },
+    /// Delete a created module
+    Module { address: String },
+}
+
+#[derive(Default)]
+struct Journal {
+    entries: Vec<UndoEntry>,
+    /// Length of `entries` when each running call started
+    checkpoints: Vec<usize>,
+}
+
+/// `Interface` wrapper reverting the changes of the calls that fail
+///
+/// The clones of a `JournaledInterface` share the same journal.
+#[derive(Clone)]
+pub struct JournaledInterface {
+    inner: Box<dyn Interface>,
+    journal: Arc<Mutex<Journal>>,
+    /// Drop the events and the async messages
+    dry_run: bool,
+}
+
+impl JournaledInterface {
+    pub fn new(inner: Box<dyn Interface>) -> Self {
+        JournaledInterface {
+            inner,
+            journal: Default::default(),
+            dry_run: false,
+        }
+    }
+
+    /// Journaled interface of an execution whose changes are all rolled back,
+    /// the events and the async messages being dropped instead of given to
+    /// the wrapped interface
+    pub fn dry_run(inner: Box<dyn Interface>) -> Self {
+        JournaledInterface {
+            dry_run: true,
+            ..JournaledInterface::new(inner)
+        }
+    }
+
+    /// Undo all the recorded changes, for example when the execution itself
+    /// failed
+    pub fn rollback(&self) -> Result<()> {
+        let entries = {
+            let mut journal = self.lock();
+            journal.checkpoints.clear();
+            std::mem::take(&mut journal.entries)
+        };
+        self.undo(entries)
+    }
+
+    /// Forget the recorded changes, they can't be undone anymore
+    pub fn commit(&self) {
+        let mut journal = self.lock();
+        journal.entries.clear();
+        journal.checkpoints.clear();
+    }
+
+    /// Lock the journal, which stays usable if a thread panicked while
+    /// holding it: each change is recorded at once
+    fn lock(&self) -> MutexGuard<'_, Journal> {
+        self.journal.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record(&self, entry: UndoEntry) {
+        self.lock().entries.push(entry);
+    }
+
+    fn undo(&self, entries: Vec<UndoEntry>) -> Result<()> {
+        for entry in entries.into_iter().rev() {
+            match entry {
+                UndoEntry::Data {
+                    address,
+                    key,
+                    previous: Some(value),
+                } => self.inner.raw_set_data_for(&address, &key, &value)?,
+                UndoEntry::Data {
+                    address,
+                    key,
+                    previous: None,
+                } => self.inner.raw_delete_data_for(&address, &key)?,
+                UndoEntry::Transfer {
+                    from_address,
+                    to_address,
+                    raw_amount,
+                } => self
+                    .inner
+                    .transfer_coins_for(&to_address, &from_address, raw_amount)?,
+                UndoEntry::Bytecode { address, previous } => {
+                    self.inner.raw_set_bytecode_for(&address, &previous)?
+                }
+                UndoEntry::Module { address } => self.inner.delete_module(&address)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Address of the module being executed
+    fn current_address(&self) -> Result<String> {
+        match self.inner.get_call_stack()?.pop() {
+            Some(address) => Ok(address),
+            None => bail!("empty call stack, the change can't be journaled"),
+        }
+    }
+
+    /// Run a change of a datastore entry, and record how to undo it if it
+    /// succeeds
+    fn record_data(
+        &self,
+        address: String,
+        key: &str,
+        change: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let previous = if self.inner.has_data_for(&address, key)? {
+            Some(self.inner.raw_get_data_for(&address, key)?)
+        } else {
+            None
+        };
+        change()?;
+        self.record(UndoEntry::Data {
+            address,
+            key: key.to_string(),
+            previous,
+        });
+        Ok(())
+    }
+
+    /// Run a change of the bytecode of an address, and record how to undo it
+    /// if it succeeds
+    fn record_bytecode(&self, address: String, change: impl FnOnce() -> Result<()>) -> Result<()> {
+        let previous = self.inner.get_module(&address)?;
+        change()?;
+        self.record(UndoEntry::Bytecode { address, previous });
+        Ok(())
+    }
+}
+
+impl InterfaceClone for JournaledInterface {
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(self.clone())
+    }
+}
+
+impl Interface for JournaledInterface {
+    fn init_call(&self, address: &str, raw_coins: u64) -> Result<Vec<u8>> {
+        let caller = self.current_address()?;
+        let checkpoint = self.lock().entries.len();
+        let module = self.inner.init_call(address, raw_coins)?;
+        let mut journal = self.lock();
+        journal.checkpoints.push(checkpoint);
+        // The coins given to the callee are part of the call
+        if raw_coins > 0 {
+            journal.entries.push(UndoEntry::Transfer {
+                from_address: caller,
+                to_address: address.to_string(),
+                raw_amount: raw_coins,
+            });
+        }
+        Ok(module)
+    }
+
+    fn finish_call(&self) -> Result<()> {
+        self.lock().checkpoints.pop();
+        self.inner.finish_call()
+    }
+
+    /// Undo the changes of the call, then finish it in the wrapped interface
+    ///
+    /// The call is finished even if its changes can't be undone, so that the
+    /// call stack of the wrapped interface stays balanced. The first error is
+    /// returned.
+    fn revert_call(&self) -> Result<()> {
+        let entries = {
+            let mut journal = self.lock();
+            let checkpoint = journal.checkpoints.pop().unwrap_or_default();
+            journal.entries.split_off(checkpoint)
+        };
+        let undone = self.undo(entries);
+        let finished = self.inner.finish_call();
+        undone.and(finished)
+    }
+
+    fn get_reentrancy_policy(&self, address: &str) -> Result<ReentrancyPolicy> {
+        self.inner.get_reentrancy_policy(address)
+    }
+
+    fn get_module(&self, address: &str) -> Result<Vec<u8>> {
+        self.inner.get_module(address)
+    }
+
+    fn get_balance(&self) -> Result<u64> {
+        self.inner.get_balance()
+    }
+
+    fn get_balance_for(&self, address: &str) -> Result<u64> {
+        self.inner.get_balance_for(address)
+    }
+
+    fn transfer_coins(&self, to_address: &str, raw_amount: u64) -> Result<()> {
+        let from_address = self.current_address()?;
+        self.inner.transfer_coins(to_address, raw_amount)?;
+        self.record(UndoEntry::Transfer {
+            from_address,
+            to_address: to_address.to_string(),
+            raw_amount,
+        });
+        Ok(())
+    }
+
+    fn transfer_coins_for(
+        &self,
+        from_address: &str,
+        to_address: &str,
+        raw_amount: u64,
+    ) -> Result<()> {
+        self.inner
+            .transfer_coins_for(from_address, to_address, raw_amount)?;
+        self.record(UndoEntry::Transfer {
+            from_address: from_address.to_string(),
+            to_address: to_address.to_string(),
+            raw_amount,
+        });
+        Ok(())
+    }
+
+    fn get_call_coins(&self) -> Result<u64> {
+        self.inner.get_call_coins()
+    }
+
+    fn raw_set_bytecode_for(&self, address: &str, bytecode: &[u8]) -> Result<()> {
+        self.record_bytecode(address.to_string(), || {
+            self.inner.raw_set_bytecode_for(address, bytecode)
+        })
+    }
+
+    fn raw_set_bytecode(&self, bytecode: &[u8]) -> Result<()> {
+        self.record_bytecode(self.current_address()?, || {
+            self.inner.raw_set_bytecode(bytecode)
+        })
+    }
+
+    fn create_module(&self, module: &[u8]) -> Result<String> {
+        let address = self.inner.create_module(module)?;
+        self.record(UndoEntry::Module {
+            address: address.clone(),
+        });
+        Ok(address)
+    }
+
+    fn delete_module(&self, address: &str) -> Result<()> {
+        self.inner.delete_module(address)
+    }
+
+    fn on_abort(&self, message: &str, filename: &str, line: i32, col: i32) -> Result<()> {
+        self.inner.on_abort(message, filename, line, col)
+    }
+
+    fn print(&self, message: &str) -> Result<()> {
+        self.inner.print(message)
+    }
+
+    fn raw_get_data(&self, key: &str) -> Result<Vec<u8>> {
+        self.inner.raw_get_data(key)
+    }
+
+    fn raw_set_data(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.record_data(self.current_address()?, key, || {
+            self.inner.raw_set_data(key, value)
+        })
+    }
+
+    fn raw_append_data(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.record_data(self.current_address()?, key, || {
+            self.inner.raw_append_data(key, value)
+        })
+    }
+
+    fn raw_delete_data(&self, key: &str) -> Result<()> {
+        self.record_data(self.current_address()?, key, || {
+            self.inner.raw_delete_data(key)
+        })
+    }
+
+    fn raw_get_data_for(&self, address: &str, key: &str) -> Result<Vec<u8>> {
+        self.inner.raw_get_data_for(address, key)
+    }
+
+    fn raw_set_data_for(&self, address: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.record_data(address.to_string(), key, || {
+            self.inner.raw_set_data_for(address, key, value)
+        })
+    }
+
+    fn raw_append_data_for(&self, address: &str, key: &str, value: &[u8]) -> Result<()> {
+        self.record_data(address.to_string(), key, || {
+            self.inner.raw_append_data_for(address, key, value)
+        })
+    }
+
+    fn raw_delete_data_for(&self, address: &str, key: &str) -> Result<()> {
+        self.record_data(address.to_string(), key, || {
+            self.inner.raw_delete_data_for(address, key)
+        })
+    }
+
+    fn has_data(&self, key: &str) -> Result<bool> {
+        self.inner.has_data(key)
+    }
+
+    fn has_data_for(&self, address: &str, key: &str) -> Result<bool> {
+        self.inner.has_data_for(address, key)
+    }
+
+    fn get_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.get_keys(prefix)
+    }
+
+    fn get_keys_for(&self, address: &str, prefix: &str) -> Result<Vec<String>> {
+        self.inner.get_keys_for(address, prefix)
+    }
+
+    fn hash(&self, data: &[u8]) -> Result<String> {
+        self.inner.hash(data)
+    }
+
+    fn signature_verify(&self, data: &[u8], signature: &str, public_key: &str) -> Result<bool> {
+        self.inner.signature_verify(data, signature, public_key)
+    }
+
+    fn address_from_public_key(&self, public_key: &str) -> Result<String> {
+        self.inner.address_from_public_key(public_key)
+    }
+
+    fn get_time(&self) -> Result<u64> {
+        self.inner.get_time()
+    }
+
+    fn unsafe_random(&self) -> Result<i64> {
+        self.inner.unsafe_random()
+    }
+
+    fn get_current_period(&self) -> Result<u64> {
+        self.inner.get_current_period()
+    }
+
+    fn get_current_thread(&self) -> Result<u8> {
+        self.inner.get_current_thread()
+    }
+
+    fn module_called(&self) -> Result<()> {
+        self.inner.module_called()
+    }
+
+    fn exit_success(&self) -> Result<()> {
+        self.inner.exit_success()
+    }
+
+    fn get_owned_addresses(&self) -> Result<Vec<String>> {
+        self.inner.get_owned_addresses()
+    }
+
+    fn get_call_stack(&self) -> Result<Vec<String>> {
+        self.inner.get_call_stack()
+    }
+
+    fn generate_event(&self, event: ScEvent) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.inner.generate_event(event)
+    }
+
+    fn send_message(
+        &self,
+        target_address: &str,
+        target_handler: &str,
+        validity_start: (u64, u8),
+        validity_end: (u64, u8),
+        max_gas: u64,
+        gas_price: u64,
+        raw_coins: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.inner.send_message(
+            target_address,
+            target_handler,
+            validity_start,
+            validity_end,
+            max_gas,
+            gas_price,
+            raw_coins,
+            data,
+        )
+    }
+}
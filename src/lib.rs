@@ -1,12 +1,42 @@
 mod abi_impl;
+mod deadline;
 mod env;
+mod error;
+mod estimate;
 mod execution_impl;
+mod gas_report;
+mod gas_schedule;
+mod journal;
+mod middlewares;
+#[cfg(any(test, feature = "mock-ledger"))]
+mod mock_ledger;
+mod module_cache;
+mod precompiled;
 mod settings;
+mod tracer;
 mod tunable_memory;
 mod types;
+mod validation;
 
-pub use execution_impl::{run_function, run_main};
+pub use error::ExecutionError;
+pub use estimate::{estimate_gas, GasEstimate};
+pub use execution_impl::{
+    run_function, run_function_readonly, run_function_readonly_with_options,
+    run_function_with_options, run_main, run_main_with_options,
+};
+pub use gas_report::{GasReport, HostFunctionGas};
+pub use gas_schedule::GasSchedule;
+pub use journal::JournaledInterface;
+#[cfg(any(test, feature = "mock-ledger"))]
+pub use mock_ledger::{MockAccount, MockCall, MockLedger, MockLedgerState, MockMessage};
+pub use module_cache::{
+    clear_module_cache, set_module_cache_config, EvictionPolicy, ModuleCacheConfig,
+};
+pub use precompiled::{load_precompiled_module, precompile_module};
+pub use settings::{GasCosts, OperatorCosts};
+pub use tracer::Tracer;
 pub use types::*;
+pub use validation::{validate_module, ValidationError, ValidationLimits};
 
 #[cfg(test)]
 mod tests;
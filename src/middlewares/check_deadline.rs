@@ -0,0 +1,200 @@
+//! Check the deadline of the execution from the webassembly code
+
+use crate::settings;
+use loupe::{MemoryUsage, MemoryUsageTracker};
+use std::mem;
+use std::sync::Mutex;
+use wasmer::wasmparser::{Operator, Type as WpType, TypeOrFuncType as WpTypeOrFuncType};
+use wasmer::{
+    ExportIndex, FunctionMiddleware, FunctionType, GlobalInit, GlobalType, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type,
+};
+use wasmer_types::{FunctionIndex, GlobalIndex, ImportIndex, ModuleInfo};
+
+/// Number of function or loop entries between two checks of the deadline
+const CHECK_INTERVAL: i32 = 1_000;
+
+/// Middleware calling the host function `settings::CHECK_DEADLINE` every
+/// `CHECK_INTERVAL` entries of a function or of a loop, so that the
+/// webassembly code stops once the deadline of the execution expired, see
+/// `deadline::Deadline`
+///
+/// The host function is added to the imports of the module, after the
+/// imported functions, which shifts the indices of the functions defined by
+/// the module. The entries are counted in a global of the instance, which is
+/// only accessed by the thread running it. Added after the metering, the
+/// instructions it adds aren't metered: the gas used doesn't depend on the
+/// deadline.
+///
+/// The modules executed without timeout are compiled without it, see
+/// `CompilationConfig::check_deadline`.
+#[derive(Debug, Default)]
+pub(crate) struct CheckDeadline {
+    indices: Mutex<Option<Indices>>,
+}
+
+/// Indices set when the module info is transformed
+#[derive(Clone, Copy, Debug)]
+struct Indices {
+    /// Index of the first function defined by the module, before the import
+    /// is added
+    first_local_function: u32,
+    check_deadline: FunctionIndex,
+    countdown: GlobalIndex,
+}
+
+#[derive(Debug)]
+struct FunctionCheckDeadline {
+    indices: Indices,
+    /// The entry of the function is checked before its first operator
+    entered: bool,
+}
+
+impl MemoryUsage for CheckDeadline {
+    fn size_of_val(&self, _: &mut dyn MemoryUsageTracker) -> usize {
+        mem::size_of_val(self)
+    }
+}
+
+impl Indices {
+    fn shift(&self, index: FunctionIndex) -> FunctionIndex {
+        if index.as_u32() < self.first_local_function {
+            index
+        } else {
+            FunctionIndex::from_u32(index.as_u32() + 1)
+        }
+    }
+}
+
+impl ModuleMiddleware for CheckDeadline {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionCheckDeadline {
+            indices: self
+                .indices
+                .lock()
+                .unwrap()
+                .expect("the module info is transformed before the functions"),
+            entered: false,
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let first_local_function = module_info.num_imported_functions as u32;
+        let check_deadline = FunctionIndex::from_u32(first_local_function);
+        let indices = Indices {
+            first_local_function,
+            check_deadline,
+            countdown: module_info
+                .globals
+                .push(GlobalType::new(Type::I32, Mutability::Var)),
+        };
+        module_info
+            .global_initializers
+            .push(GlobalInit::I32Const(CHECK_INTERVAL));
+
+        // Shift the indices of the functions defined by the module
+        let signature = module_info
+            .signatures
+            .push(FunctionType::new(vec![], vec![]));
+        let mut functions: Vec<_> = module_info.functions.values().copied().collect();
+        functions.insert(first_local_function as usize, signature);
+        module_info.functions = functions.into_iter().collect();
+        for export in module_info.exports.values_mut() {
+            if let ExportIndex::Function(index) = export {
+                *index = indices.shift(*index);
+            }
+        }
+        if let Some(start) = &mut module_info.start_function {
+            *start = indices.shift(*start);
+        }
+        for initializer in &mut module_info.table_initializers {
+            for index in initializer.elements.iter_mut() {
+                *index = indices.shift(*index);
+            }
+        }
+        for elements in module_info.passive_elements.values_mut() {
+            for index in elements.iter_mut() {
+                *index = indices.shift(*index);
+            }
+        }
+        for initializer in module_info.global_initializers.values_mut() {
+            if let GlobalInit::RefFunc(index) = initializer {
+                *index = indices.shift(*index);
+            }
+        }
+        module_info.function_names = mem::take(&mut module_info.function_names)
+            .into_iter()
+            .map(|(index, name)| (indices.shift(index), name))
+            .collect();
+
+        let import = module_info.imports.len() as u32;
+        module_info.imports.insert(
+            (
+                "massa".to_string(),
+                settings::CHECK_DEADLINE.to_string(),
+                import,
+            ),
+            ImportIndex::Function(check_deadline),
+        );
+        module_info.num_imported_functions += 1;
+        *self.indices.lock().unwrap() = Some(indices);
+    }
+}
+
+impl FunctionCheckDeadline {
+    fn check(&self, state: &mut MiddlewareReaderState<'_>) {
+        let global_index = self.indices.countdown.as_u32();
+        state.extend(&[
+            Operator::GlobalGet { global_index },
+            Operator::I32Const { value: 1 },
+            Operator::I32Sub,
+            Operator::GlobalSet { global_index },
+            Operator::GlobalGet { global_index },
+            Operator::I32Eqz,
+            Operator::If {
+                ty: WpTypeOrFuncType::Type(WpType::EmptyBlockType),
+            },
+            Operator::I32Const {
+                value: CHECK_INTERVAL,
+            },
+            Operator::GlobalSet { global_index },
+            Operator::Call {
+                function_index: self.indices.check_deadline.as_u32(),
+            },
+            Operator::End,
+        ]);
+    }
+}
+
+impl FunctionMiddleware for FunctionCheckDeadline {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        if !self.entered {
+            self.entered = true;
+            self.check(state);
+        }
+        match operator {
+            Operator::Call { function_index } => state.push_operator(Operator::Call {
+                function_index: self
+                    .indices
+                    .shift(FunctionIndex::from_u32(function_index))
+                    .as_u32(),
+            }),
+            Operator::RefFunc { function_index } => state.push_operator(Operator::RefFunc {
+                function_index: self
+                    .indices
+                    .shift(FunctionIndex::from_u32(function_index))
+                    .as_u32(),
+            }),
+            Operator::Loop { .. } => {
+                state.push_operator(operator);
+                self.check(state);
+            }
+            operator => state.push_operator(operator),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+//! Defer the execution of the webassembly `start` function
+
+use crate::settings;
+use loupe::MemoryUsage;
+use wasmer::{ExportIndex, FunctionMiddleware, LocalFunctionIndex, ModuleMiddleware};
+use wasmer_types::ModuleInfo;
+
+/// Middleware removing the `start` function of a module and exporting it
+/// under the name `settings::DEFERRED_START` instead.
+///
+/// The start function is called by wasmer when the instance is created,
+/// which is before we have the possibility to set the gas limit of the
+/// execution. Deferring it allows us to compile a module once, independently
+/// of any gas limit, and to call the start function ourselves as soon as the
+/// metering points are set.
+#[derive(Debug, Default, MemoryUsage)]
+pub(crate) struct DeferredStart;
+
+#[derive(Debug)]
+struct FunctionDeferredStart;
+
+impl ModuleMiddleware for DeferredStart {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionDeferredStart)
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        if let Some(start) = module_info.start_function.take() {
+            module_info.exports.insert(
+                settings::DEFERRED_START.to_string(),
+                ExportIndex::Function(start),
+            );
+        }
+    }
+}
+
+/// Functions are left untouched
+impl FunctionMiddleware for FunctionDeferredStart {}
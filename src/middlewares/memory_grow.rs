@@ -0,0 +1,225 @@
+//! Charge the pages of memory allocated by `memory.grow`
+
+use crate::settings;
+use loupe::{MemoryUsage, MemoryUsageTracker};
+use std::mem;
+use std::sync::Mutex;
+use wasmer::wasmparser::{Operator, Type as WpType, TypeOrFuncType as WpTypeOrFuncType};
+use wasmer::{
+    ExportIndex, FunctionMiddleware, GlobalInit, GlobalType, Instance, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type,
+};
+use wasmer_types::{GlobalIndex, ModuleInfo};
+
+/// Middleware charging `GasCosts::memory_page` for each page allocated by
+/// `memory.grow`, and failing the instruction if the pages used by the whole
+/// call stack would exceed `GasCosts::max_number_of_pages`
+///
+/// The gas is taken from the globals of the `Metering` middleware, which must
+/// be added before this one. The pages used by the callers are given in a
+/// global exported under the name `settings::CALLERS_MEMORY_PAGES`, see
+/// `set_callers_memory_pages`.
+#[derive(Debug)]
+pub(crate) struct MemoryGrow {
+    page_cost: u64,
+    max_pages: u32,
+    /// Indexes of the globals, set when the module info is transformed
+    globals: Mutex<Option<MemoryGrowGlobals>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MemoryGrowGlobals {
+    remaining_points: GlobalIndex,
+    points_exhausted: GlobalIndex,
+    callers_pages: GlobalIndex,
+    /// Number of pages given to `memory.grow`, which is needed twice
+    delta: GlobalIndex,
+}
+
+#[derive(Debug)]
+struct FunctionMemoryGrow {
+    page_cost: u64,
+    max_pages: u32,
+    globals: MemoryGrowGlobals,
+}
+
+impl MemoryGrow {
+    pub fn new(page_cost: u64, max_pages: u32) -> Self {
+        MemoryGrow {
+            page_cost,
+            max_pages,
+            globals: Mutex::new(None),
+        }
+    }
+}
+
+impl MemoryUsage for MemoryGrow {
+    fn size_of_val(&self, _: &mut dyn MemoryUsageTracker) -> usize {
+        mem::size_of_val(self)
+    }
+}
+
+fn exported_global(module_info: &ModuleInfo, name: &str) -> GlobalIndex {
+    match module_info.exports.get(name) {
+        Some(ExportIndex::Global(index)) => *index,
+        _ => panic!("the Metering middleware must be added before MemoryGrow"),
+    }
+}
+
+impl ModuleMiddleware for MemoryGrow {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionMemoryGrow {
+            page_cost: self.page_cost,
+            max_pages: self.max_pages,
+            globals: self
+                .globals
+                .lock()
+                .unwrap()
+                .expect("the module info is transformed before the functions"),
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let add_global = |module_info: &mut ModuleInfo| {
+            module_info
+                .global_initializers
+                .push(GlobalInit::I32Const(0));
+            module_info
+                .globals
+                .push(GlobalType::new(Type::I32, Mutability::Var))
+        };
+        let callers_pages = add_global(module_info);
+        module_info.exports.insert(
+            settings::CALLERS_MEMORY_PAGES.to_string(),
+            ExportIndex::Global(callers_pages),
+        );
+        let delta = add_global(module_info);
+        *self.globals.lock().unwrap() = Some(MemoryGrowGlobals {
+            remaining_points: exported_global(module_info, "wasmer_metering_remaining_points"),
+            points_exhausted: exported_global(module_info, "wasmer_metering_points_exhausted"),
+            callers_pages,
+            delta,
+        });
+    }
+}
+
+impl FunctionMiddleware for FunctionMemoryGrow {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        let (mem, mem_byte) = match operator {
+            Operator::MemoryGrow { mem, mem_byte } => (mem, mem_byte),
+            operator => {
+                state.push_operator(operator);
+                return Ok(());
+            }
+        };
+        let remaining_points = self.globals.remaining_points.as_u32();
+        let points_exhausted = self.globals.points_exhausted.as_u32();
+        let callers_pages = self.globals.callers_pages.as_u32();
+        let delta = self.globals.delta.as_u32();
+        let mut cost = vec![
+            Operator::GlobalGet {
+                global_index: delta,
+            },
+            Operator::I64ExtendI32U,
+            Operator::I64Const {
+                value: self.page_cost as i64,
+            },
+            Operator::I64Mul,
+        ];
+        // The pages are at most `max_pages` here. If their cost can overflow,
+        // it saturates to `u64::MAX` instead of wrapping to a small amount.
+        if let Some(max_delta) = u64::MAX
+            .checked_div(self.page_cost)
+            .filter(|max_delta| *max_delta < self.max_pages as u64)
+        {
+            cost.insert(0, Operator::I64Const { value: -1 });
+            cost.extend([
+                Operator::GlobalGet {
+                    global_index: delta,
+                },
+                Operator::I64ExtendI32U,
+                Operator::I64Const {
+                    value: max_delta as i64,
+                },
+                Operator::I64GtU,
+                Operator::Select,
+            ]);
+        }
+        state.extend(&[
+            Operator::GlobalSet {
+                global_index: delta,
+            },
+            // Pages of the whole call stack after the growth
+            Operator::GlobalGet {
+                global_index: callers_pages,
+            },
+            Operator::I64ExtendI32U,
+            Operator::MemorySize { mem, mem_byte },
+            Operator::I64ExtendI32U,
+            Operator::I64Add,
+            Operator::GlobalGet {
+                global_index: delta,
+            },
+            Operator::I64ExtendI32U,
+            Operator::I64Add,
+            Operator::I64Const {
+                value: self.max_pages as i64,
+            },
+            Operator::I64GtU,
+            // Fail like a memory that can't grow
+            Operator::If {
+                ty: WpTypeOrFuncType::Type(WpType::I32),
+            },
+            Operator::I32Const { value: -1 },
+            Operator::Else,
+            // Trap like the metering when there isn't enough gas
+            Operator::GlobalGet {
+                global_index: remaining_points,
+            },
+        ]);
+        state.extend(&cost);
+        state.extend(&[
+            Operator::I64LtU,
+            Operator::If {
+                ty: WpTypeOrFuncType::Type(WpType::EmptyBlockType),
+            },
+            Operator::I32Const { value: 1 },
+            Operator::GlobalSet {
+                global_index: points_exhausted,
+            },
+            Operator::Unreachable,
+            Operator::End,
+            Operator::GlobalGet {
+                global_index: remaining_points,
+            },
+        ]);
+        state.extend(&cost);
+        state.extend(&[
+            Operator::I64Sub,
+            Operator::GlobalSet {
+                global_index: remaining_points,
+            },
+            Operator::GlobalGet {
+                global_index: delta,
+            },
+            operator,
+            Operator::End,
+        ]);
+        Ok(())
+    }
+}
+
+/// Set the number of pages of memory used by the callers of an instance
+/// before it's called
+pub(crate) fn set_callers_memory_pages(instance: &Instance, pages: u32) {
+    instance
+        .exports
+        .get_global(settings::CALLERS_MEMORY_PAGES)
+        .expect("the modules are compiled with the MemoryGrow middleware")
+        .set((pages as i32).into())
+        .expect("the callers memory pages global is a mutable i32");
+}
@@ -0,0 +1,13 @@
+//! Custom wasmer middlewares applied when a module is compiled
+
+mod check_deadline;
+mod deferred_start;
+mod memory_grow;
+mod reject_floats;
+mod stack_height;
+
+pub(crate) use check_deadline::CheckDeadline;
+pub(crate) use deferred_start::DeferredStart;
+pub(crate) use memory_grow::{set_callers_memory_pages, MemoryGrow};
+pub(crate) use reject_floats::RejectFloats;
+pub(crate) use stack_height::{set_stack_height, FrameCosts, StackHeight};
@@ -0,0 +1,53 @@
+//! Reject the float instructions when a module is compiled
+
+use crate::validation::is_float;
+use loupe::MemoryUsage;
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    FunctionMiddleware, LocalFunctionIndex, MiddlewareError, MiddlewareReaderState,
+    ModuleMiddleware,
+};
+
+/// Middleware failing the compilation of a module using floats
+///
+/// `canonicalize_nans` only makes the NaN deterministic, the other results of
+/// the float instructions can still differ from a CPU to another in some edge
+/// cases. In the strict determinism mode, see `GasCosts::reject_floats`, the
+/// modules must do without floats.
+#[derive(Debug, Default, MemoryUsage)]
+pub(crate) struct RejectFloats;
+
+#[derive(Debug)]
+struct FunctionRejectFloats {
+    function: LocalFunctionIndex,
+}
+
+impl ModuleMiddleware for RejectFloats {
+    fn generate_function_middleware(
+        &self,
+        function: LocalFunctionIndex,
+    ) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionRejectFloats { function })
+    }
+}
+
+impl FunctionMiddleware for FunctionRejectFloats {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        if is_float(&operator) {
+            return Err(MiddlewareError::new(
+                "RejectFloats",
+                format!(
+                    "float instruction {:?} in local function {}",
+                    operator,
+                    self.function.as_u32()
+                ),
+            ));
+        }
+        state.push_operator(operator);
+        Ok(())
+    }
+}
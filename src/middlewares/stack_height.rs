@@ -0,0 +1,537 @@
+//! Limit the height of the webassembly stack
+
+use crate::settings;
+use loupe::{MemoryUsage, MemoryUsageTracker};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use wasmer::wasmparser::{
+    BinaryReaderError, FuncType, FunctionBody, ImportSectionEntryType, Operator, Parser, Payload,
+    Type as WpType, TypeDef, TypeOrFuncType as WpTypeOrFuncType,
+};
+use wasmer::{
+    ExportIndex, FunctionMiddleware, GlobalInit, GlobalType, Instance, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type,
+};
+use wasmer_types::{GlobalIndex, ModuleInfo};
+
+/// Cost of the frame of a function on top of its locals and operands, so
+/// that a function without any still counts
+const ACTIVATION_FRAME_COST: u32 = 2;
+
+/// Middleware trapping when the height of the stack exceeds
+/// `GasCosts::max_stack_height`
+///
+/// `enable_stack_check` only stops the execution when the native stack of
+/// the thread overflows, which depends on the host. The height of the stack
+/// is counted in a global exported under the name `settings::STACK_HEIGHT`,
+/// increased by the frame cost of the callee before each call and decreased
+/// after it, see `FrameCosts`. A called module starts from the height of its
+/// caller, see `set_stack_height`, so that the limit also applies to the
+/// nested calls.
+#[derive(Debug)]
+pub(crate) struct StackHeight {
+    max_stack_height: u32,
+    frame_costs: Arc<FrameCosts>,
+    /// Index of the global, set when the module info is transformed
+    global_index: Mutex<Option<GlobalIndex>>,
+}
+
+#[derive(Debug)]
+struct FunctionStackHeight {
+    max_stack_height: u32,
+    frame_costs: Arc<FrameCosts>,
+    global_index: GlobalIndex,
+}
+
+impl StackHeight {
+    pub fn new(max_stack_height: u32, frame_costs: FrameCosts) -> Self {
+        StackHeight {
+            max_stack_height,
+            frame_costs: Arc::new(frame_costs),
+            global_index: Mutex::new(None),
+        }
+    }
+}
+
+/// Cost of the frames of the functions of a module, in values: the locals of
+/// the function, parameters excluded as they're counted by the caller, the
+/// maximum height of its operand stack and `ACTIVATION_FRAME_COST`, as in
+/// the stack limiter of parity-wasm
+///
+/// The imported functions only cost `ACTIVATION_FRAME_COST`, the modules
+/// they call counting their own frames. An indirect call costs as much as the
+/// most expensive function of its type.
+#[derive(Debug, Default)]
+pub(crate) struct FrameCosts {
+    /// Frame cost of each function, imported or defined, by index
+    functions: Vec<u32>,
+    /// Frame cost of an indirect call, by type index
+    indirect_calls: Vec<u32>,
+}
+
+impl FrameCosts {
+    /// Compute the frame costs of the functions of a module in the binary
+    /// format
+    pub fn new(bytecode: &[u8]) -> Result<Self, BinaryReaderError> {
+        let mut types = Vec::new();
+        // Type index of each function, imported or defined
+        let mut function_types = Vec::new();
+        let mut functions = Vec::new();
+        for payload in Parser::new(0).parse_all(bytecode) {
+            match payload? {
+                Payload::TypeSection(reader) => {
+                    for ty in reader {
+                        types.push(match ty? {
+                            TypeDef::Func(ty) => ty,
+                            _ => FuncType {
+                                params: Box::new([]),
+                                returns: Box::new([]),
+                            },
+                        });
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        if let ImportSectionEntryType::Function(ty) = import?.ty {
+                            function_types.push(ty);
+                            functions.push(ACTIVATION_FRAME_COST);
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        function_types.push(ty?);
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let ty = function_types
+                        .get(functions.len())
+                        .and_then(|ty| types.get(*ty as usize));
+                    let returns = ty.map_or(0, |ty| ty.returns.len() as u32);
+                    let locals = count_locals(&body)?;
+                    let operands = max_operand_height(&body, returns, &types, &function_types)?;
+                    functions.push(
+                        locals
+                            .saturating_add(operands)
+                            .saturating_add(ACTIVATION_FRAME_COST),
+                    );
+                }
+                _ => (),
+            }
+        }
+        let indirect_calls = types
+            .iter()
+            .map(|ty| {
+                function_types
+                    .iter()
+                    .zip(&functions)
+                    .filter(|(function_type, _)| types.get(**function_type as usize) == Some(ty))
+                    .map(|(_, cost)| *cost)
+                    .max()
+                    .unwrap_or(ACTIVATION_FRAME_COST)
+            })
+            .collect();
+        Ok(FrameCosts {
+            functions,
+            indirect_calls,
+        })
+    }
+
+    fn call(&self, function_index: u32) -> u32 {
+        self.functions
+            .get(function_index as usize)
+            .copied()
+            .unwrap_or(ACTIVATION_FRAME_COST)
+    }
+
+    fn indirect_call(&self, type_index: u32) -> u32 {
+        self.indirect_calls
+            .get(type_index as usize)
+            .copied()
+            .unwrap_or(ACTIVATION_FRAME_COST)
+    }
+}
+
+fn count_locals(body: &FunctionBody) -> Result<u32, BinaryReaderError> {
+    let mut locals = 0u32;
+    let mut reader = body.get_locals_reader()?;
+    for _ in 0..reader.get_count() {
+        locals = locals.saturating_add(reader.read()?.0);
+    }
+    Ok(locals)
+}
+
+/// Block of a function body, the function itself being the outermost one
+struct Block {
+    /// Height of the operand stack when the block is entered, its parameters
+    /// excluded
+    start: u32,
+    results: u32,
+}
+
+/// Maximum height of the operand stack of a function body
+///
+/// After an instruction that never falls through, the operand stack is
+/// unreachable until the end of the block: its height is reset to the start
+/// of the block, and the instructions can't pop below it.
+fn max_operand_height(
+    body: &FunctionBody,
+    returns: u32,
+    types: &[FuncType],
+    function_types: &[u32],
+) -> Result<u32, BinaryReaderError> {
+    let signature = |type_index: u32| {
+        types.get(type_index as usize).map_or((0, 0), |ty| {
+            (ty.params.len() as u32, ty.returns.len() as u32)
+        })
+    };
+    let block_signature = |ty: WpTypeOrFuncType| match ty {
+        WpTypeOrFuncType::Type(WpType::EmptyBlockType) => (0, 0),
+        WpTypeOrFuncType::Type(_) => (0, 1),
+        WpTypeOrFuncType::FuncType(type_index) => signature(type_index),
+    };
+    let mut blocks = vec![Block {
+        start: 0,
+        results: returns,
+    }];
+    let (mut height, mut max_height) = (0u32, 0u32);
+    for operator in body.get_operators_reader()? {
+        let start = blocks.last().map_or(0, |block| block.start);
+        let pop = |height: u32, count: u32| height.saturating_sub(count).max(start);
+        let (pops, pushes) = match operator? {
+            Operator::Block { ty } | Operator::Loop { ty } => {
+                let (params, results) = block_signature(ty);
+                blocks.push(Block {
+                    start: pop(height, params),
+                    results,
+                });
+                continue;
+            }
+            Operator::If { ty } => {
+                height = pop(height, 1);
+                let (params, results) = block_signature(ty);
+                blocks.push(Block {
+                    start: pop(height, params),
+                    results,
+                });
+                continue;
+            }
+            Operator::Else => {
+                height = start;
+                continue;
+            }
+            Operator::End => {
+                if let Some(block) = blocks.pop() {
+                    height = block.start.saturating_add(block.results);
+                    max_height = max_height.max(height);
+                }
+                continue;
+            }
+            Operator::Unreachable
+            | Operator::Br { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return => {
+                height = start;
+                continue;
+            }
+            Operator::Call { function_index } => signature(
+                function_types
+                    .get(function_index as usize)
+                    .copied()
+                    .unwrap_or(u32::MAX),
+            ),
+            Operator::CallIndirect { index, .. } => {
+                let (params, results) = signature(index);
+                (params + 1, results)
+            }
+            operator => stack_effect(&operator),
+        };
+        height = pop(height, pops).saturating_add(pushes);
+        max_height = max_height.max(height);
+    }
+    Ok(max_height)
+}
+
+/// Number of operands popped and pushed by an instruction that isn't a
+/// control instruction or a call
+///
+/// The instructions of the features disabled by the runtime aren't listed,
+/// they're counted as pushing an operand.
+fn stack_effect(operator: &Operator) -> (u32, u32) {
+    use Operator::*;
+    match operator {
+        Nop | DataDrop { .. } | ElemDrop { .. } => (0, 0),
+        LocalGet { .. }
+        | GlobalGet { .. }
+        | I32Const { .. }
+        | I64Const { .. }
+        | F32Const { .. }
+        | F64Const { .. }
+        | MemorySize { .. }
+        | RefNull { .. }
+        | RefFunc { .. }
+        | TableSize { .. } => (0, 1),
+        Drop | LocalSet { .. } | GlobalSet { .. } | BrIf { .. } => (1, 0),
+        LocalTee { .. }
+        | I32Load { .. }
+        | I64Load { .. }
+        | F32Load { .. }
+        | F64Load { .. }
+        | I32Load8S { .. }
+        | I32Load8U { .. }
+        | I32Load16S { .. }
+        | I32Load16U { .. }
+        | I64Load8S { .. }
+        | I64Load8U { .. }
+        | I64Load16S { .. }
+        | I64Load16U { .. }
+        | I64Load32S { .. }
+        | I64Load32U { .. }
+        | MemoryGrow { .. }
+        | RefIsNull
+        | TableGet { .. }
+        | I32Eqz
+        | I64Eqz
+        | I32Clz
+        | I32Ctz
+        | I32Popcnt
+        | I64Clz
+        | I64Ctz
+        | I64Popcnt
+        | F32Abs
+        | F32Neg
+        | F32Ceil
+        | F32Floor
+        | F32Trunc
+        | F32Nearest
+        | F32Sqrt
+        | F64Abs
+        | F64Neg
+        | F64Ceil
+        | F64Floor
+        | F64Trunc
+        | F64Nearest
+        | F64Sqrt
+        | I32WrapI64
+        | I32TruncF32S
+        | I32TruncF32U
+        | I32TruncF64S
+        | I32TruncF64U
+        | I64ExtendI32S
+        | I64ExtendI32U
+        | I64TruncF32S
+        | I64TruncF32U
+        | I64TruncF64S
+        | I64TruncF64U
+        | F32ConvertI32S
+        | F32ConvertI32U
+        | F32ConvertI64S
+        | F32ConvertI64U
+        | F32DemoteF64
+        | F64ConvertI32S
+        | F64ConvertI32U
+        | F64ConvertI64S
+        | F64ConvertI64U
+        | F64PromoteF32
+        | I32ReinterpretF32
+        | I64ReinterpretF64
+        | F32ReinterpretI32
+        | F64ReinterpretI64
+        | I32Extend8S
+        | I32Extend16S
+        | I64Extend8S
+        | I64Extend16S
+        | I64Extend32S
+        | I32TruncSatF32S
+        | I32TruncSatF32U
+        | I32TruncSatF64S
+        | I32TruncSatF64U
+        | I64TruncSatF32S
+        | I64TruncSatF32U
+        | I64TruncSatF64S
+        | I64TruncSatF64U => (1, 1),
+        I32Store { .. }
+        | I64Store { .. }
+        | F32Store { .. }
+        | F64Store { .. }
+        | I32Store8 { .. }
+        | I32Store16 { .. }
+        | I64Store8 { .. }
+        | I64Store16 { .. }
+        | I64Store32 { .. }
+        | TableSet { .. } => (2, 0),
+        I32Eq
+        | I32Ne
+        | I32LtS
+        | I32LtU
+        | I32GtS
+        | I32GtU
+        | I32LeS
+        | I32LeU
+        | I32GeS
+        | I32GeU
+        | I64Eq
+        | I64Ne
+        | I64LtS
+        | I64LtU
+        | I64GtS
+        | I64GtU
+        | I64LeS
+        | I64LeU
+        | I64GeS
+        | I64GeU
+        | F32Eq
+        | F32Ne
+        | F32Lt
+        | F32Gt
+        | F32Le
+        | F32Ge
+        | F64Eq
+        | F64Ne
+        | F64Lt
+        | F64Gt
+        | F64Le
+        | F64Ge
+        | I32Add
+        | I32Sub
+        | I32Mul
+        | I32DivS
+        | I32DivU
+        | I32RemS
+        | I32RemU
+        | I32And
+        | I32Or
+        | I32Xor
+        | I32Shl
+        | I32ShrS
+        | I32ShrU
+        | I32Rotl
+        | I32Rotr
+        | I64Add
+        | I64Sub
+        | I64Mul
+        | I64DivS
+        | I64DivU
+        | I64RemS
+        | I64RemU
+        | I64And
+        | I64Or
+        | I64Xor
+        | I64Shl
+        | I64ShrS
+        | I64ShrU
+        | I64Rotl
+        | I64Rotr
+        | F32Add
+        | F32Sub
+        | F32Mul
+        | F32Div
+        | F32Min
+        | F32Max
+        | F32Copysign
+        | F64Add
+        | F64Sub
+        | F64Mul
+        | F64Div
+        | F64Min
+        | F64Max
+        | F64Copysign
+        | TableGrow { .. } => (2, 1),
+        Select | TypedSelect { .. } => (3, 1),
+        MemoryInit { .. }
+        | MemoryCopy { .. }
+        | MemoryFill { .. }
+        | TableInit { .. }
+        | TableCopy { .. }
+        | TableFill { .. } => (3, 0),
+        _ => (0, 1),
+    }
+}
+
+impl MemoryUsage for StackHeight {
+    fn size_of_val(&self, _: &mut dyn MemoryUsageTracker) -> usize {
+        mem::size_of_val(self)
+    }
+}
+
+impl ModuleMiddleware for StackHeight {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionStackHeight {
+            max_stack_height: self.max_stack_height,
+            frame_costs: self.frame_costs.clone(),
+            global_index: self
+                .global_index
+                .lock()
+                .unwrap()
+                .expect("the module info is transformed before the functions"),
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let global_index = module_info
+            .globals
+            .push(GlobalType::new(Type::I64, Mutability::Var));
+        module_info
+            .global_initializers
+            .push(GlobalInit::I64Const(0));
+        module_info.exports.insert(
+            settings::STACK_HEIGHT.to_string(),
+            ExportIndex::Global(global_index),
+        );
+        *self.global_index.lock().unwrap() = Some(global_index);
+    }
+}
+
+impl FunctionMiddleware for FunctionStackHeight {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        let cost = match operator {
+            Operator::Call { function_index } => self.frame_costs.call(function_index),
+            Operator::CallIndirect { index, .. } => self.frame_costs.indirect_call(index),
+            _ => {
+                state.push_operator(operator);
+                return Ok(());
+            }
+        };
+        // The height is an i64, so that adding a `u32` cost to a height below
+        // a `u32` maximum can't overflow
+        let global_index = self.global_index.as_u32();
+        state.extend(&[
+            Operator::GlobalGet { global_index },
+            Operator::I64Const { value: cost.into() },
+            Operator::I64Add,
+            Operator::GlobalSet { global_index },
+            Operator::GlobalGet { global_index },
+            Operator::I64Const {
+                value: self.max_stack_height.into(),
+            },
+            Operator::I64GtU,
+            Operator::If {
+                ty: WpTypeOrFuncType::Type(WpType::EmptyBlockType),
+            },
+            Operator::Unreachable,
+            Operator::End,
+        ]);
+        state.push_operator(operator);
+        state.extend(&[
+            Operator::GlobalGet { global_index },
+            Operator::I64Const { value: cost.into() },
+            Operator::I64Sub,
+            Operator::GlobalSet { global_index },
+        ]);
+        Ok(())
+    }
+}
+
+/// Set the height of the stack of an instance before it's called
+pub(crate) fn set_stack_height(instance: &Instance, height: u32) {
+    instance
+        .exports
+        .get_global(settings::STACK_HEIGHT)
+        .expect("the modules are compiled with the StackHeight middleware")
+        .set(i64::from(height).into())
+        .expect("the stack height global is a mutable i64");
+}
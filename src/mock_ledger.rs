@@ -0,0 +1,512 @@
+//! In-memory `Interface`, for the unit tests of the modules
+//!
+//! Available with the `mock-ledger` feature. A `MockLedger` keeps the whole
+//! state of the ledger in a `MockLedgerState`, which can be loaded from and
+//! saved to JSON to set up a test and check its outcome:
+//!
+//! ```json
+//! {
+//!   "accounts": {
+//!     "A1": {
+//!       "balance": 100,
+//!       "bytecode": [],
+//!       "datastore": {},
+//!       "reentrancy_policy": "Allow"
+//!     }
+//!   },
+//!   "call_stack": [{ "address": "A1", "coins": 0 }],
+//!   ...
+//! }
+//! ```
+//!
+//! The signatures are mocked: a signature is valid if it's the one returned by
+//! `MockLedger::sign`.
+
+use crate::types::{Interface, InterfaceClone, ReentrancyPolicy, ScEvent};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Account of the ledger
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MockAccount {
+    pub balance: u64,
+    /// Bytecode of the module, empty if the account has none
+    pub bytecode: Vec<u8>,
+    pub datastore: BTreeMap<String, Vec<u8>>,
+    pub reentrancy_policy: ReentrancyPolicy,
+}
+
+/// Call on the call stack
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MockCall {
+    pub address: String,
+    /// Coins given to the call
+    pub coins: u64,
+}
+
+/// Async message sent by a module
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MockMessage {
+    pub sender: String,
+    pub target_address: String,
+    pub target_handler: String,
+    pub validity_start: (u64, u8),
+    pub validity_end: (u64, u8),
+    pub max_gas: u64,
+    pub gas_price: u64,
+    pub raw_coins: u64,
+    pub data: Vec<u8>,
+}
+
+/// State of a `MockLedger`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MockLedgerState {
+    pub accounts: BTreeMap<String, MockAccount>,
+    /// The current call last, the first one being the caller of the execution
+    pub call_stack: Vec<MockCall>,
+    /// Addresses returned by `get_owned_addresses`, including the created
+    /// modules
+    pub owned_addresses: Vec<String>,
+    /// Queue of the async messages, in sending order
+    pub messages: Vec<MockMessage>,
+    pub events: Vec<ScEvent>,
+    /// Messages given to `print`
+    pub prints: Vec<String>,
+    /// Aborts of the modules, as `message at filename:line:col`
+    pub aborts: Vec<String>,
+    /// Millisecond unix timestamp returned by `get_time`
+    pub time: u64,
+    pub period: u64,
+    pub thread: u8,
+    /// State of the generator of `unsafe_random`
+    pub random_seed: u64,
+    /// Number of modules created, used to build their addresses
+    pub created_modules: u64,
+}
+
+impl MockLedgerState {
+    fn current_call(&self) -> Result<&MockCall> {
+        match self.call_stack.last() {
+            Some(call) => Ok(call),
+            None => bail!("empty call stack"),
+        }
+    }
+
+    fn current_address(&self) -> Result<String> {
+        Ok(self.current_call()?.address.clone())
+    }
+
+    fn account(&self, address: &str) -> Result<&MockAccount> {
+        match self.accounts.get(address) {
+            Some(account) => Ok(account),
+            None => bail!("unknown address {}", address),
+        }
+    }
+
+    fn account_mut(&mut self, address: &str) -> Result<&mut MockAccount> {
+        match self.accounts.get_mut(address) {
+            Some(account) => Ok(account),
+            None => bail!("unknown address {}", address),
+        }
+    }
+
+    fn transfer(&mut self, from_address: &str, to_address: &str, raw_amount: u64) -> Result<()> {
+        let from_balance = match self.account(from_address)?.balance.checked_sub(raw_amount) {
+            Some(balance) => balance,
+            None => bail!("not enough coins on {}", from_address),
+        };
+        if from_address == to_address {
+            return Ok(());
+        }
+        let to_balance = self.accounts.get(to_address).map_or(0, |to| to.balance);
+        let to_balance = match to_balance.checked_add(raw_amount) {
+            Some(balance) => balance,
+            None => bail!("balance overflow on {}", to_address),
+        };
+        self.account_mut(from_address)?.balance = from_balance;
+        self.accounts
+            .entry(to_address.to_string())
+            .or_default()
+            .balance = to_balance;
+        Ok(())
+    }
+}
+
+/// In-memory `Interface`, see the module documentation
+///
+/// The clones of a `MockLedger` share the same state.
+#[derive(Clone, Default)]
+pub struct MockLedger(Arc<Mutex<MockLedgerState>>);
+
+impl MockLedger {
+    pub fn new(state: MockLedgerState) -> Self {
+        MockLedger(Arc::new(Mutex::new(state)))
+    }
+
+    /// Ledger with an account for the caller of the execution, which is the
+    /// only address of the call stack
+    pub fn with_caller(address: &str, balance: u64) -> Self {
+        let mut state = MockLedgerState::default();
+        state.accounts.insert(
+            address.to_string(),
+            MockAccount {
+                balance,
+                ..Default::default()
+            },
+        );
+        state.call_stack.push(MockCall {
+            address: address.to_string(),
+            coins: 0,
+        });
+        MockLedger::new(state)
+    }
+
+    /// Copy of the current state, to check the outcome of a test
+    pub fn state(&self) -> MockLedgerState {
+        self.lock().clone()
+    }
+
+    /// Change the state, for example to set up a test
+    ///
+    /// The state is locked during `f`, which must not use the ledger.
+    pub fn with_state<R>(&self, f: impl FnOnce(&mut MockLedgerState) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockLedgerState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let state = serde_json::from_str(json).context("invalid JSON mock ledger")?;
+        Ok(MockLedger::new(state))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&*self.lock())?)
+    }
+
+    /// Load a ledger saved with `save`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read mock ledger {}", path.display()))?;
+        MockLedger::from_json(&json)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_json()?)
+            .with_context(|| format!("cannot write mock ledger {}", path.display()))
+    }
+
+    /// Mocked signature of the data, accepted by `signature_verify`
+    pub fn sign(data: &[u8], public_key: &str) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(public_key.as_bytes());
+        hasher.update(data);
+        hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl InterfaceClone for MockLedger {
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(self.clone())
+    }
+}
+
+impl Interface for MockLedger {
+    fn init_call(&self, address: &str, raw_coins: u64) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        let bytecode = state.account(address)?.bytecode.clone();
+        if bytecode.is_empty() {
+            bail!("no module at {}", address);
+        }
+        let caller = state.current_address()?;
+        state.transfer(&caller, address, raw_coins)?;
+        state.call_stack.push(MockCall {
+            address: address.to_string(),
+            coins: raw_coins,
+        });
+        Ok(bytecode)
+    }
+
+    fn finish_call(&self) -> Result<()> {
+        match self.lock().call_stack.pop() {
+            Some(_) => Ok(()),
+            None => bail!("empty call stack"),
+        }
+    }
+
+    fn get_reentrancy_policy(&self, address: &str) -> Result<ReentrancyPolicy> {
+        let state = self.lock();
+        Ok(state
+            .accounts
+            .get(address)
+            .map_or(ReentrancyPolicy::Allow, |account| account.reentrancy_policy))
+    }
+
+    fn get_module(&self, address: &str) -> Result<Vec<u8>> {
+        Ok(self.lock().account(address)?.bytecode.clone())
+    }
+
+    fn get_balance(&self) -> Result<u64> {
+        let address = self.lock().current_address()?;
+        self.get_balance_for(&address)
+    }
+
+    fn get_balance_for(&self, address: &str) -> Result<u64> {
+        let state = self.lock();
+        Ok(state
+            .accounts
+            .get(address)
+            .map_or(0, |account| account.balance))
+    }
+
+    fn transfer_coins(&self, to_address: &str, raw_amount: u64) -> Result<()> {
+        let mut state = self.lock();
+        let from_address = state.current_address()?;
+        state.transfer(&from_address, to_address, raw_amount)
+    }
+
+    fn transfer_coins_for(
+        &self,
+        from_address: &str,
+        to_address: &str,
+        raw_amount: u64,
+    ) -> Result<()> {
+        self.lock().transfer(from_address, to_address, raw_amount)
+    }
+
+    fn get_call_coins(&self) -> Result<u64> {
+        Ok(self.lock().current_call()?.coins)
+    }
+
+    fn raw_set_bytecode_for(&self, address: &str, bytecode: &[u8]) -> Result<()> {
+        self.lock().account_mut(address)?.bytecode = bytecode.to_vec();
+        Ok(())
+    }
+
+    fn raw_set_bytecode(&self, bytecode: &[u8]) -> Result<()> {
+        let address = self.lock().current_address()?;
+        self.raw_set_bytecode_for(&address, bytecode)
+    }
+
+    fn create_module(&self, module: &[u8]) -> Result<String> {
+        let mut state = self.lock();
+        state.created_modules += 1;
+        let address = format!("A{}", state.created_modules);
+        if state.accounts.contains_key(&address) {
+            bail!("address {} already used", address);
+        }
+        state.accounts.insert(
+            address.clone(),
+            MockAccount {
+                bytecode: module.to_vec(),
+                ..Default::default()
+            },
+        );
+        state.owned_addresses.push(address.clone());
+        Ok(address)
+    }
+
+    fn delete_module(&self, address: &str) -> Result<()> {
+        let mut state = self.lock();
+        if state.accounts.remove(address).is_none() {
+            bail!("unknown address {}", address);
+        }
+        state.owned_addresses.retain(|owned| owned != address);
+        Ok(())
+    }
+
+    fn on_abort(&self, message: &str, filename: &str, line: i32, col: i32) -> Result<()> {
+        let abort = format!("{} at {}:{}:{}", message, filename, line, col);
+        self.lock().aborts.push(abort);
+        Ok(())
+    }
+
+    fn print(&self, message: &str) -> Result<()> {
+        self.lock().prints.push(message.to_string());
+        Ok(())
+    }
+
+    fn raw_get_data(&self, key: &str) -> Result<Vec<u8>> {
+        let address = self.lock().current_address()?;
+        self.raw_get_data_for(&address, key)
+    }
+
+    fn raw_set_data(&self, key: &str, value: &[u8]) -> Result<()> {
+        let address = self.lock().current_address()?;
+        self.raw_set_data_for(&address, key, value)
+    }
+
+    fn raw_append_data(&self, key: &str, value: &[u8]) -> Result<()> {
+        let address = self.lock().current_address()?;
+        self.raw_append_data_for(&address, key, value)
+    }
+
+    fn raw_delete_data(&self, key: &str) -> Result<()> {
+        let address = self.lock().current_address()?;
+        self.raw_delete_data_for(&address, key)
+    }
+
+    fn raw_get_data_for(&self, address: &str, key: &str) -> Result<Vec<u8>> {
+        match self.lock().account(address)?.datastore.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => bail!("no data for key {} at {}", key, address),
+        }
+    }
+
+    fn raw_set_data_for(&self, address: &str, key: &str, value: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        let datastore = &mut state.account_mut(address)?.datastore;
+        datastore.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn raw_append_data_for(&self, address: &str, key: &str, value: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        match state.account_mut(address)?.datastore.get_mut(key) {
+            Some(data) => data.extend_from_slice(value),
+            None => bail!("no data for key {} at {}", key, address),
+        }
+        Ok(())
+    }
+
+    fn raw_delete_data_for(&self, address: &str, key: &str) -> Result<()> {
+        let mut state = self.lock();
+        match state.account_mut(address)?.datastore.remove(key) {
+            Some(_) => Ok(()),
+            None => bail!("no data for key {} at {}", key, address),
+        }
+    }
+
+    fn has_data(&self, key: &str) -> Result<bool> {
+        let address = self.lock().current_address()?;
+        self.has_data_for(&address, key)
+    }
+
+    fn has_data_for(&self, address: &str, key: &str) -> Result<bool> {
+        let state = self.lock();
+        Ok(matches!(
+            state.accounts.get(address),
+            Some(account) if account.datastore.contains_key(key)
+        ))
+    }
+
+    fn get_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let address = self.lock().current_address()?;
+        self.get_keys_for(&address, prefix)
+    }
+
+    fn get_keys_for(&self, address: &str, prefix: &str) -> Result<Vec<String>> {
+        let state = self.lock();
+        Ok(state
+            .account(address)?
+            .datastore
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn hash(&self, data: &[u8]) -> Result<String> {
+        Ok(blake3::hash(data).to_hex().to_string())
+    }
+
+    fn signature_verify(&self, data: &[u8], signature: &str, public_key: &str) -> Result<bool> {
+        Ok(MockLedger::sign(data, public_key) == signature)
+    }
+
+    fn address_from_public_key(&self, public_key: &str) -> Result<String> {
+        Ok(format!("A{}", &self.hash(public_key.as_bytes())?[..32]))
+    }
+
+    fn get_time(&self) -> Result<u64> {
+        Ok(self.lock().time)
+    }
+
+    /// xorshift64 of the seed, deterministic to reproduce the tests
+    fn unsafe_random(&self) -> Result<i64> {
+        let mut state = self.lock();
+        let mut x = state.random_seed.max(1);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.random_seed = x;
+        Ok(x as i64)
+    }
+
+    fn get_current_period(&self) -> Result<u64> {
+        Ok(self.lock().period)
+    }
+
+    fn get_current_thread(&self) -> Result<u8> {
+        Ok(self.lock().thread)
+    }
+
+    fn module_called(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn exit_success(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_owned_addresses(&self) -> Result<Vec<String>> {
+        Ok(self.lock().owned_addresses.clone())
+    }
+
+    fn get_call_stack(&self) -> Result<Vec<String>> {
+        let state = self.lock();
+        Ok(state
+            .call_stack
+            .iter()
+            .map(|call| call.address.clone())
+            .collect())
+    }
+
+    fn generate_event(&self, event: ScEvent) -> Result<()> {
+        self.lock().events.push(event);
+        Ok(())
+    }
+
+    /// Queue the message, the coins being taken from the sender
+    fn send_message(
+        &self,
+        target_address: &str,
+        target_handler: &str,
+        validity_start: (u64, u8),
+        validity_end: (u64, u8),
+        max_gas: u64,
+        gas_price: u64,
+        raw_coins: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut state = self.lock();
+        let sender = state.current_address()?;
+        let account = state.account_mut(&sender)?;
+        match account.balance.checked_sub(raw_coins) {
+            Some(balance) => account.balance = balance,
+            None => bail!("not enough coins on {}", sender),
+        }
+        state.messages.push(MockMessage {
+            sender,
+            target_address: target_address.to_string(),
+            target_handler: target_handler.to_string(),
+            validity_start,
+            validity_end,
+            max_gas,
+            gas_price,
+            raw_coins,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+//! Cache of compiled modules
+//!
+//! Compiling a module with Singlepass is by far the most expensive part of
+//! an execution. The cache keeps the compiled `wasmer::Module` of the
+//! recently executed bytecodes so that a call only has to create a new
+//! `Instance`.
+
+use crate::execution_impl::compile_module;
+use crate::settings::{GasCosts, OperatorCosts};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use wasmer::Module;
+
+/// Everything, except the bytecode itself, that changes the output of the
+/// compilation of a module.
+///
+/// Two modules compiled with different configurations are never mixed up in
+/// the cache.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CompilationConfig {
+    /// Maximum number of pages of the memory, enforced by the store tunables
+    pub max_number_of_pages: u32,
+    /// Cost of a page, charged by the instructions added before `memory.grow`
+    pub memory_page_cost: u64,
+    /// Cost of the instructions, compiled in the module by the metering
+    pub operator_costs: OperatorCosts,
+    /// Whether the float instructions are rejected by the compilation
+    pub reject_floats: bool,
+    /// Maximum height of the stack, checked by the instructions added to the
+    /// module
+    pub max_stack_height: u32,
+    /// Whether the webassembly code checks the deadline of the execution,
+    /// only the executions with a timeout need it
+    pub check_deadline: bool,
+}
+
+impl CompilationConfig {
+    /// Configuration of the modules executed with the given gas schedule and
+    /// without timeout
+    pub fn new(gas_costs: &GasCosts) -> Self {
+        CompilationConfig {
+            max_number_of_pages: gas_costs.max_number_of_pages,
+            memory_page_cost: gas_costs.memory_page,
+            operator_costs: gas_costs.operators.clone(),
+            reject_floats: gas_costs.reject_floats,
+            max_stack_height: gas_costs.max_stack_height,
+            check_deadline: false,
+        }
+    }
+
+    /// Digest of the configuration, used to make sure a precompiled module
+    /// has been compiled with the same configuration.
+    ///
+    /// The fields are hashed in declaration order, the integers in little
+    /// endian, so that the fingerprint doesn't depend on the compiler or on
+    /// the host.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let CompilationConfig {
+            max_number_of_pages,
+            memory_page_cost,
+            operator_costs,
+            reject_floats,
+            max_stack_height,
+            check_deadline,
+        } = self;
+        let OperatorCosts {
+            default,
+            local,
+            control_flow,
+            load,
+            store,
+            memory_grow,
+            call,
+            call_indirect,
+            division,
+        } = operator_costs;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&max_number_of_pages.to_le_bytes());
+        hasher.update(&memory_page_cost.to_le_bytes());
+        for cost in [
+            default,
+            local,
+            control_flow,
+            load,
+            store,
+            memory_grow,
+            call,
+            call_indirect,
+            division,
+        ] {
+            hasher.update(&cost.to_le_bytes());
+        }
+        hasher.update(&[*reject_floats as u8]);
+        hasher.update(&max_stack_height.to_le_bytes());
+        hasher.update(&[*check_deadline as u8]);
+        *hasher.finalize().as_bytes()
+    }
+}
+
+/// Rule used to choose which module leaves the cache when it is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the module that hasn't been used for the longest time
+    LeastRecentlyUsed,
+    /// Evict the module that has been compiled first
+    FirstInFirstOut,
+}
+
+/// Configuration of the compiled modules cache
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleCacheConfig {
+    /// Maximum number of compiled modules kept in memory, a value of zero
+    /// disables the cache.
+    pub max_entries: usize,
+    /// Rule used to choose which module is evicted when the cache is full
+    pub eviction_policy: EvictionPolicy,
+}
+
+impl Default for ModuleCacheConfig {
+    fn default() -> Self {
+        ModuleCacheConfig {
+            max_entries: 1000,
+            eviction_policy: EvictionPolicy::LeastRecentlyUsed,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct ModuleKey {
+    bytecode_hash: [u8; 32],
+    config: CompilationConfig,
+}
+
+impl ModuleKey {
+    pub fn new(bytecode: &[u8], config: &CompilationConfig) -> Self {
+        ModuleKey {
+            bytecode_hash: *blake3::hash(bytecode).as_bytes(),
+            config: config.clone(),
+        }
+    }
+
+    pub fn bytecode_hash(&self) -> &[u8; 32] {
+        &self.bytecode_hash
+    }
+}
+
+struct CacheEntry {
+    module: Module,
+    /// Tick at which the entry has been inserted
+    inserted_at: u64,
+    /// Tick at which the entry has been read for the last time
+    used_at: u64,
+}
+
+/// Counters of the accesses to a module of the cache since the start of the
+/// process
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CacheStats {
+    /// Modules found in the cache
+    pub hits: u64,
+    /// Modules compiled because they weren't in the cache
+    pub compilations: u64,
+    /// Modules loaded from a precompiled artifact
+    pub loads: u64,
+}
+
+pub(crate) struct ModuleCache {
+    config: ModuleCacheConfig,
+    entries: HashMap<ModuleKey, CacheEntry>,
+    /// Logical clock, incremented on each access
+    tick: u64,
+    /// Accesses to each module, kept by the tests only
+    #[cfg(test)]
+    stats: HashMap<ModuleKey, CacheStats>,
+}
+
+impl ModuleCache {
+    pub fn new(config: ModuleCacheConfig) -> Self {
+        ModuleCache {
+            config,
+            entries: HashMap::new(),
+            tick: 0,
+            #[cfg(test)]
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &ModuleKey) -> Option<Module> {
+        self.tick += 1;
+        let tick = self.tick;
+        let module = self.entries.get_mut(key).map(|entry| {
+            entry.used_at = tick;
+            entry.module.clone()
+        });
+        #[cfg(test)]
+        if module.is_some() {
+            self.count(key, |stats| &mut stats.hits);
+        }
+        module
+    }
+
+    pub fn insert(&mut self, key: ModuleKey, module: Module) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+        self.tick += 1;
+        if !self.entries.contains_key(&key) {
+            while self.entries.len() >= self.config.max_entries {
+                self.evict();
+            }
+        }
+        self.entries.insert(
+            key,
+            CacheEntry {
+                module,
+                inserted_at: self.tick,
+                used_at: self.tick,
+            },
+        );
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn set_config(&mut self, config: ModuleCacheConfig) {
+        self.config = config;
+        while self.entries.len() > self.config.max_entries {
+            self.evict();
+        }
+    }
+
+    #[cfg(test)]
+    fn count(&mut self, key: &ModuleKey, counter: fn(&mut CacheStats) -> &mut u64) {
+        *counter(self.stats.entry(key.clone()).or_default()) += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Remove one entry following the eviction policy
+    fn evict(&mut self) {
+        let policy = self.config.eviction_policy;
+        let victim = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| match policy {
+                EvictionPolicy::LeastRecentlyUsed => entry.used_at,
+                EvictionPolicy::FirstInFirstOut => entry.inserted_at,
+            })
+            .map(|(key, _)| key.clone());
+        if let Some(key) = victim {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    static ref MODULE_CACHE: Mutex<ModuleCache> =
+        Mutex::new(ModuleCache::new(ModuleCacheConfig::default()));
+}
+
+/// Lock the cache, which stays usable if a thread panicked while holding it:
+/// its entries are only replaced as a whole
+fn cache() -> MutexGuard<'static, ModuleCache> {
+    MODULE_CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return the compiled module of the given bytecode, compiling it only if it
+/// isn't already in the cache.
+pub(crate) fn get_or_compile(bytecode: &[u8], config: &CompilationConfig) -> Result<Module> {
+    let key = ModuleKey::new(bytecode, config);
+    if let Some(module) = cache().get(&key) {
+        return Ok(module);
+    }
+    // The lock isn't held during the compilation, so other executions can
+    // still use the cache in the meantime.
+    let module = compile_module(bytecode, config)?;
+    let mut cache = cache();
+    #[cfg(test)]
+    cache.count(&key, |stats| &mut stats.compilations);
+    cache.insert(key, module.clone());
+    Ok(module)
+}
+
+/// Add a module loaded from a precompiled artifact to the cache
+pub(crate) fn insert_loaded(key: ModuleKey, module: Module) {
+    let mut cache = cache();
+    #[cfg(test)]
+    cache.count(&key, |stats| &mut stats.loads);
+    cache.insert(key, module);
+}
+
+/// Counters of the accesses to the module of the given key, used by the tests
+/// to check where a module comes from
+#[cfg(test)]
+pub(crate) fn stats(key: &ModuleKey) -> CacheStats {
+    cache().stats.get(key).copied().unwrap_or_default()
+}
+
+/// Change the size limit and the eviction policy of the compiled modules
+/// cache. Modules are evicted right away if the cache is now too large.
+pub fn set_module_cache_config(config: ModuleCacheConfig) {
+    cache().set_config(config);
+}
+
+/// Remove all the compiled modules from the cache
+pub fn clear_module_cache() {
+    cache().clear();
+}
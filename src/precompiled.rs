@@ -0,0 +1,130 @@
+//! Precompiled modules that can be persisted by the node
+//!
+//! An artifact is a module compiled by `precompile_module` and serialized
+//! with `Module::serialize`, prefixed by a header:
+//!
+//! | field                         | size               |
+//! |-------------------------------|--------------------|
+//! | magic `MASSASC\0`             | 8 bytes            |
+//! | artifact format version (LE)  | 4 bytes            |
+//! | runtime version length        | 1 byte             |
+//! | runtime version               | variable           |
+//! | wasmer version length         | 1 byte             |
+//! | wasmer version                | variable           |
+//! | compilation config fingerprint| 32 bytes           |
+//! | bytecode hash (blake3)        | 32 bytes           |
+//! | serialized module hash        | 32 bytes           |
+//! | serialized module             | rest of the buffer |
+//!
+//! An artifact of another format, produced by another version of the runtime
+//! or of wasmer, with another compilation configuration or from another
+//! bytecode is rejected by `load_precompiled_module`.
+
+use crate::execution_impl::deserialize_module;
+use crate::module_cache::{self, CompilationConfig, ModuleKey};
+use crate::settings::GasCosts;
+use anyhow::{bail, Result};
+
+const MAGIC: &[u8; 8] = b"MASSASC\0";
+
+/// Version of the compiled code and of the layout of the artifacts, to
+/// increase whenever the compilation changes without a new version of the
+/// runtime, for example when a middleware changes
+pub(crate) const ARTIFACT_VERSION: u32 = 2;
+
+const RUNTIME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Compile a module for the given gas schedule and return an artifact that
+/// can be stored and loaded back with `load_precompiled_module` after a
+/// restart.
+///
+/// The compiled module is also added to the cache of compiled modules. It is
+/// used by the executions without timeout: the ones with a timeout compile the
+/// module again, with the checks of their deadline.
+pub fn precompile_module(bytecode: &[u8], gas_costs: &GasCosts) -> Result<Vec<u8>> {
+    let config = CompilationConfig::new(gas_costs);
+    let module = module_cache::get_or_compile(bytecode, &config)?;
+    let serialized = module.serialize()?;
+    let key = ModuleKey::new(bytecode, &config);
+
+    let mut artifact = Vec::with_capacity(serialized.len() + 128);
+    artifact.extend_from_slice(MAGIC);
+    artifact.extend_from_slice(&ARTIFACT_VERSION.to_le_bytes());
+    for version in [RUNTIME_VERSION, wasmer::VERSION] {
+        artifact.push(version.len() as u8);
+        artifact.extend_from_slice(version.as_bytes());
+    }
+    artifact.extend_from_slice(&config.fingerprint());
+    artifact.extend_from_slice(key.bytecode_hash());
+    artifact.extend_from_slice(blake3::hash(&serialized).as_bytes());
+    artifact.extend_from_slice(&serialized);
+    Ok(artifact)
+}
+
+/// Load an artifact created by `precompile_module` from the given bytecode in
+/// the cache of compiled modules, the next executions of the bytecode won't
+/// need to compile it.
+///
+/// Fails if the artifact has another format, has been created by another
+/// version of the runtime or of wasmer, for a gas schedule that compiles modules differently or from another
+/// bytecode, or if it is corrupted.
+///
+/// The artifact contains native code: it must come from a trusted storage,
+/// like the node's own disk, and never from the network.
+pub fn load_precompiled_module(
+    bytecode: &[u8],
+    artifact: &[u8],
+    gas_costs: &GasCosts,
+) -> Result<()> {
+    let mut reader = artifact;
+    if take(&mut reader, MAGIC.len())? != MAGIC {
+        bail!("not a precompiled module");
+    }
+    if take(&mut reader, 4)? != ARTIFACT_VERSION.to_le_bytes() {
+        bail!("precompiled module of another artifact format");
+    }
+    if take_version(&mut reader)? != RUNTIME_VERSION.as_bytes() {
+        bail!("precompiled module built by another version of the runtime");
+    }
+    if take_version(&mut reader)? != wasmer::VERSION.as_bytes() {
+        bail!("precompiled module built by another version of wasmer");
+    }
+    let config = CompilationConfig::new(gas_costs);
+    if take(&mut reader, 32)? != config.fingerprint() {
+        bail!("precompiled module built with another compilation configuration");
+    }
+    // The cache is keyed by the hash of the bytecode, which is recomputed so
+    // that the module can't be used for another bytecode
+    let key = ModuleKey::new(bytecode, &config);
+    if take(&mut reader, 32)? != key.bytecode_hash() {
+        bail!("precompiled module built from another bytecode");
+    }
+    let serialized_hash = take(&mut reader, 32)?;
+    if serialized_hash != blake3::hash(reader).as_bytes() {
+        bail!("corrupted precompiled module");
+    }
+    // The serialized module is read in place and must be aligned, which isn't
+    // guaranteed at an arbitrary offset of the artifact.
+    let serialized = reader.to_vec();
+    // Safety: the module has been serialized by `precompile_module` with the
+    // same versions and configuration, and its integrity is checked.
+    let module = unsafe { deserialize_module(&serialized, &config)? };
+    module_cache::insert_loaded(key, module);
+    Ok(())
+}
+
+/// Read the next `len` bytes of the artifact
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if reader.len() < len {
+        bail!("truncated precompiled module");
+    }
+    let (value, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(value)
+}
+
+/// Read a version, preceded by its length
+fn take_version<'a>(reader: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = take(reader, 1)?[0] as usize;
+    take(reader, len)
+}
@@ -1,236 +1,253 @@
-use cornetto::Cornetto;
+use crate::validation::ValidationLimits;
+use serde::{Deserialize, Serialize};
+use wasmer::wasmparser::Operator;
 
 // main function name in the webassembly module
 pub(crate) const MAIN: &str = "main";
 
-// Metering private implementation
-#[allow(dead_code)]
-#[derive(Cornetto)]
-struct Metering {
-    #[cornetto(mut, 200)]
+// prefix of the names of the exports and imports added by the runtime, which the modules can't use
+pub(crate) const RESERVED_PREFIX: &str = "__massa_";
+
+// name under which the start function of a module is exported, see `middlewares::DeferredStart`
+pub(crate) const DEFERRED_START: &str = "__massa_deferred_start";
+
+// name under which the height of the stack is exported, see `middlewares::StackHeight`
+pub(crate) const STACK_HEIGHT: &str = "__massa_stack_height";
+
+// name under which the pages of memory used by the callers are exported, see `middlewares::MemoryGrow`
+pub(crate) const CALLERS_MEMORY_PAGES: &str = "__massa_callers_memory_pages";
+
+// name under which the host function checking the deadline is imported from the `massa` namespace, see `middlewares::CheckDeadline`
+pub(crate) const CHECK_DEADLINE: &str = "__massa_check_deadline";
+
+// maximum number of topics of an event
+pub(crate) const MAX_EVENT_TOPICS: usize = 4;
+
+/// Gas schedule of an execution: the cost of each ABI and the limits of the
+/// module.
+///
+/// The schedule is given to `run_main` and `run_function` and stays the same
+/// for all the calls of the execution. Fields ending with `_mult` are charged
+/// per byte of the corresponding argument, on top of the `_const` cost.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GasCosts {
     pub call: u64,
-    #[cornetto(const, 200)]
+    /// Gas kept for the caller of `assembly_script_try_call*`, which the
+    /// callee can't use, so that the caller can handle the failure of a
+    /// callee that ran out of gas
+    pub try_call_reserved_gas: u64,
     pub generate_event: u64,
-    #[cornetto(const, 200)]
+    pub generate_event_mult: usize,
     pub transfer: u64,
-    #[cornetto(const, 200)]
     pub get_balance: u64,
-    #[cornetto(const, 100)]
     pub set_data_const: u64,
-    #[cornetto(const, 1)]
     pub set_data_key_mult: usize,
-    #[cornetto(const, 1)]
     pub set_data_value_mult: usize,
-    #[cornetto(const, 100)]
     pub get_data_const: u64,
-    #[cornetto(const, 1)]
     pub get_data_key_mult: usize,
-    #[cornetto(const, 1)]
     pub get_data_value_mult: usize,
-    #[cornetto(const, 100)]
     pub delete_data_const: u64,
-    #[cornetto(const, 1)]
     pub delete_data_key_mult: usize,
-    #[cornetto(const, 100)]
     pub append_data_const: u64,
-    #[cornetto(const, 1)]
     pub append_data_key_mult: usize,
-    #[cornetto(const, 1)]
     pub append_data_value_mult: usize,
-    #[cornetto(const, 100)]
     pub has_data_const: u64,
-    #[cornetto(const, 1)]
     pub has_data_key_mult: usize,
-    #[cornetto(const, 1)]
+    pub get_keys_const: u64,
+    pub get_keys_prefix_mult: usize,
+    /// Charged for each key starting with the prefix given to
+    /// `assembly_script_get_keys`, all of them being fetched and sorted to
+    /// return a page
+    pub get_keys_per_key: usize,
     pub create_sc_mult: usize,
-    #[cornetto(const, 200)]
     pub print: u64,
-    #[cornetto(const, 200)]
     pub remaining_gas: u64,
-    #[cornetto(const, 100)]
     pub get_hash_const: u64,
-    #[cornetto(const, 1)]
     pub hash_per_byte: usize,
-    #[cornetto(const, 200)]
     pub get_owned_addrs: u64,
-    #[cornetto(const, 200)]
     pub get_call_stack: u64,
-    #[cornetto(const, 100)]
     pub signature_verify_const: u64,
-    #[cornetto(const, 1)]
     pub signature_verify_data_mult: usize,
-    #[cornetto(const, 100)]
     pub address_from_public_key: u64,
-    #[cornetto(const, 100)]
     pub unsafe_random: u64,
-    #[cornetto(const, 100)]
     pub get_time: u64,
-    #[cornetto(const, 100)]
     pub get_call_coins: u64,
-    #[cornetto(const, 50)]
     pub get_current_period: u64,
-    #[cornetto(const, 50)]
     pub get_current_thread: u64,
-    #[cornetto(const, 64)]
+    /// Maximum number of pages of memory used by an execution and the
+    /// modules it calls, changing it requires to compile the modules again
     pub max_number_of_pages: u32,
-    #[cornetto(const, 100)]
+    /// Charged for each page of memory, the initial pages when a module is
+    /// instantiated and the pages allocated by `memory.grow`. Changing it
+    /// requires to compile the modules again
+    pub memory_page: u64,
     pub send_message: u64,
-    #[cornetto(const, 1)]
     pub set_bytecode_mult: usize,
-    #[cornetto(const, 100)]
     pub set_bytecode_const: u64,
-}
-
-pub(crate) fn metering_call() -> u64 {
-    METERING.call()
-}
-
-pub(crate) fn metering_get_call_coins() -> u64 {
-    METERING.get_call_coins()
-}
-
-pub(crate) fn metering_generate_event() -> u64 {
-    METERING.generate_event()
-}
-
-pub(crate) fn metering_transfer() -> u64 {
-    METERING.transfer()
-}
-
-pub(crate) fn metering_get_balance() -> u64 {
-    METERING.get_balance()
-}
-
-pub(crate) fn metering_print() -> u64 {
-    METERING.print()
-}
-
-pub(crate) fn metering_create_sc_mult() -> usize {
-    METERING.create_sc_mult()
-}
-
-pub(crate) fn metering_remaining_gas() -> u64 {
-    METERING.remaining_gas()
-}
-
-pub(crate) fn metering_set_data_const() -> u64 {
-    METERING.set_data_const()
-}
-
-pub(crate) fn metering_set_data_key_mult() -> usize {
-    METERING.set_data_key_mult()
-}
-
-pub(crate) fn metering_set_data_value_mult() -> usize {
-    METERING.set_data_value_mult()
-}
-
-pub(crate) fn metering_get_data_const() -> u64 {
-    METERING.get_data_const()
-}
-
-pub(crate) fn metering_get_data_key_mult() -> usize {
-    METERING.get_data_key_mult()
-}
-
-pub(crate) fn metering_get_data_value_mult() -> usize {
-    METERING.get_data_value_mult()
-}
-
-pub(crate) fn metering_append_data_const() -> u64 {
-    METERING.append_data_const()
-}
-
-pub(crate) fn metering_append_data_key_mult() -> usize {
-    METERING.append_data_key_mult()
-}
-
-pub(crate) fn metering_append_data_value_mult() -> usize {
-    METERING.append_data_value_mult()
-}
-
-pub(crate) fn metering_delete_data_const() -> u64 {
-    METERING.delete_data_const()
-}
-
-pub(crate) fn metering_delete_data_key_mult() -> usize {
-    METERING.delete_data_key_mult()
-}
-
-pub(crate) fn metering_has_data_const() -> u64 {
-    METERING.has_data_const()
-}
-
-pub(crate) fn metering_has_data_key_mult() -> usize {
-    METERING.has_data_key_mult()
-}
-
-pub(crate) fn metering_hash_const() -> u64 {
-    METERING.get_hash_const()
-}
-
-pub(crate) fn metering_hash_per_byte() -> usize {
-    METERING.hash_per_byte()
-}
-
-pub(crate) fn metering_get_owned_addrs() -> u64 {
-    METERING.get_owned_addrs()
-}
-
-pub(crate) fn metering_get_call_stack() -> u64 {
-    METERING.get_call_stack()
-}
-
-pub(crate) fn metering_signature_verify_const() -> u64 {
-    METERING.signature_verify_const()
-}
-
-pub(crate) fn metering_signature_verify_data_mult() -> usize {
-    METERING.signature_verify_data_mult()
-}
-
-pub(crate) fn metering_address_from_public_key() -> u64 {
-    METERING.address_from_public_key()
-}
-
-pub(crate) fn metering_unsafe_random() -> u64 {
-    METERING.unsafe_random()
-}
-
-pub(crate) fn metering_get_time() -> u64 {
-    METERING.get_time()
-}
-
-pub(crate) fn metering_get_current_period() -> u64 {
-    METERING.get_current_period()
-}
-
-pub(crate) fn metering_get_current_thread() -> u64 {
-    METERING.get_current_thread()
-}
-
-pub(crate) fn max_number_of_pages() -> u32 {
-    METERING.max_number_of_pages()
-}
-
-pub(crate) fn metering_send_message() -> u64 {
-    METERING.send_message()
-}
-
-pub(crate) fn metering_set_bytecode_mult() -> usize {
-    METERING.set_bytecode_mult()
-}
-
-pub(crate) fn metering_set_bytecode_const() -> u64 {
-    METERING.set_bytecode_const()
-}
-
-#[cfg(test)]
-pub(crate) fn set_metering(call_price: u64) {
-    METERING._reset(call_price);
-}
-
-#[cfg(test)]
-pub(crate) fn reset_metering() {
-    METERING._reset(DEFAULT_METERING_CALL);
+    /// Maximum number of nested calls, a call beyond it fails with
+    /// `ExecutionError::CallDepth`
+    pub max_call_depth: u32,
+    /// Maximum height of the webassembly stack, counting the modules called,
+    /// beyond which the execution traps. Each call adds the frame of the
+    /// callee: its locals and the maximum height of its operand stack, in
+    /// values. Changing it requires to compile the modules again
+    pub max_stack_height: u32,
+    /// Strict determinism mode: reject the modules using floats when they're
+    /// compiled, changing it requires to compile the modules again
+    pub reject_floats: bool,
+    /// Limits checked by `validate_module` before a module is created or its
+    /// bytecode changed
+    pub validation: ValidationLimits,
+    /// Cost of the webassembly instructions, changing it requires to compile
+    /// the modules again
+    pub operators: OperatorCosts,
+}
+
+impl Default for GasCosts {
+    fn default() -> Self {
+        GasCosts {
+            call: 200,
+            try_call_reserved_gas: 10_000,
+            generate_event: 200,
+            generate_event_mult: 1,
+            transfer: 200,
+            get_balance: 200,
+            set_data_const: 100,
+            set_data_key_mult: 1,
+            set_data_value_mult: 1,
+            get_data_const: 100,
+            get_data_key_mult: 1,
+            get_data_value_mult: 1,
+            delete_data_const: 100,
+            delete_data_key_mult: 1,
+            append_data_const: 100,
+            append_data_key_mult: 1,
+            append_data_value_mult: 1,
+            has_data_const: 100,
+            has_data_key_mult: 1,
+            get_keys_const: 100,
+            get_keys_prefix_mult: 1,
+            get_keys_per_key: 10,
+            create_sc_mult: 1,
+            print: 200,
+            remaining_gas: 200,
+            get_hash_const: 100,
+            hash_per_byte: 1,
+            get_owned_addrs: 200,
+            get_call_stack: 200,
+            signature_verify_const: 100,
+            signature_verify_data_mult: 1,
+            address_from_public_key: 100,
+            unsafe_random: 100,
+            get_time: 100,
+            get_call_coins: 100,
+            get_current_period: 50,
+            get_current_thread: 50,
+            max_number_of_pages: 64,
+            memory_page: 1000,
+            send_message: 100,
+            set_bytecode_mult: 1,
+            set_bytecode_const: 100,
+            max_call_depth: 32,
+            max_stack_height: 16_384,
+            reject_floats: false,
+            validation: ValidationLimits::default(),
+            operators: OperatorCosts::default(),
+        }
+    }
+}
+
+/// Cost of each class of webassembly instruction, charged by the metering
+/// middleware when the instruction is executed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperatorCosts {
+    /// Instructions without a specific class, like arithmetic or constants
+    pub default: u64,
+    /// `local.*` and `global.*` instructions
+    pub local: u64,
+    /// Blocks, branches and returns
+    pub control_flow: u64,
+    /// Memory loads
+    pub load: u64,
+    /// Memory stores
+    pub store: u64,
+    /// `memory.grow`
+    pub memory_grow: u64,
+    /// Direct calls
+    pub call: u64,
+    /// Calls through a table
+    pub call_indirect: u64,
+    /// Integer and float divisions and remainders
+    pub division: u64,
+}
+
+impl Default for OperatorCosts {
+    fn default() -> Self {
+        OperatorCosts {
+            default: 1,
+            local: 1,
+            control_flow: 1,
+            load: 1,
+            store: 1,
+            memory_grow: 1,
+            call: 1,
+            call_indirect: 1,
+            division: 1,
+        }
+    }
+}
+
+impl OperatorCosts {
+    /// Cost of an instruction, used as the cost function of the metering
+    pub fn cost(&self, operator: &Operator) -> u64 {
+        use Operator::*;
+        match operator {
+            LocalGet { .. }
+            | LocalSet { .. }
+            | LocalTee { .. }
+            | GlobalGet { .. }
+            | GlobalSet { .. } => self.local,
+            Block { .. }
+            | Loop { .. }
+            | If { .. }
+            | Else
+            | End
+            | Br { .. }
+            | BrIf { .. }
+            | BrTable { .. }
+            | Return
+            | Unreachable => self.control_flow,
+            I32Load { .. }
+            | I64Load { .. }
+            | F32Load { .. }
+            | F64Load { .. }
+            | I32Load8S { .. }
+            | I32Load8U { .. }
+            | I32Load16S { .. }
+            | I32Load16U { .. }
+            | I64Load8S { .. }
+            | I64Load8U { .. }
+            | I64Load16S { .. }
+            | I64Load16U { .. }
+            | I64Load32S { .. }
+            | I64Load32U { .. } => self.load,
+            I32Store { .. }
+            | I64Store { .. }
+            | F32Store { .. }
+            | F64Store { .. }
+            | I32Store8 { .. }
+            | I32Store16 { .. }
+            | I64Store8 { .. }
+            | I64Store16 { .. }
+            | I64Store32 { .. } => self.store,
+            MemoryGrow { .. } => self.memory_grow,
+            Call { .. } | ReturnCall { .. } => self.call,
+            CallIndirect { .. } | ReturnCallIndirect { .. } => self.call_indirect,
+            I32DivS | I32DivU | I32RemS | I32RemU | I64DivS | I64DivU | I64RemS | I64RemU
+            | F32Div | F64Div => self.division,
+            _ => self.default,
+        }
+    }
 }
@@ -1,244 +1,1770 @@
 /// THIS FILE SHOULD TEST THE ABI, NOT THE MOCKED INTERFACE
 use crate::{
-    run_function, run_main, settings,
-    types::{Interface, InterfaceClone},
+    clear_module_cache, estimate_gas,
+    execution_impl::compile_module,
+    load_precompiled_module,
+    module_cache::{self, CacheStats, CompilationConfig, ModuleCache, ModuleKey},
+    precompile_module,
+    precompiled::ARTIFACT_VERSION,
+    run_function, run_function_readonly, run_function_readonly_with_options,
+    run_function_with_options, run_main,
+    types::{Interface, ReentrancyPolicy, ReturnValue, ScEvent},
+    validate_module, EvictionPolicy, ExecutionError, ExecutionOptions, ExecutionResult, GasCosts,
+    GasSchedule, JournaledInterface, MockLedger, ModuleCacheConfig, OperatorCosts, Tracer,
+    ValidationError, ValidationLimits,
 };
-use anyhow::{bail, Result};
 use serial_test::serial;
 use std::sync::{Arc, Mutex};
-pub type Ledger = std::collections::BTreeMap<String, Vec<u8>>; // Byttecode instead of String
+use std::time::Duration;
 
-#[derive(Clone)]
-struct TestInterface(Arc<Mutex<Ledger>>);
-
-impl InterfaceClone for TestInterface {
-    fn clone_box(&self) -> Box<dyn Interface> {
-        Box::new(self.clone())
-    }
+/// AssemblyScript objects laid out in the memory of a `test_module`
+///
+/// Each object is preceded by its class id and its size, like the objects
+/// allocated by `__new`.
+#[derive(Default)]
+struct TestData {
+    segments: String,
+    end: u32,
 }
 
-impl Interface for TestInterface {
-    fn init_call(&self, address: &str, _raw_coins: u64) -> Result<Vec<u8>> {
-        match self
-            .0
-            .lock()
-            .unwrap()
-            .clone()
-            .get::<String>(&address.to_string())
-        {
-            Some(module) => Ok(module.clone()),
-            _ => bail!("Cannot find module for address {}", address),
-        }
+impl TestData {
+    /// Add a `string`, returns the pointer to it
+    fn string(&mut self, value: &str) -> u32 {
+        let bytes: Vec<u8> = value.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        self.object(1, &bytes)
     }
 
-    fn finish_call(&self) -> Result<()> {
-        Ok(())
+    /// Add an `ArrayBuffer`, returns the pointer to it
+    fn bytes(&mut self, value: &[u8]) -> u32 {
+        self.object(0, value)
     }
 
-    fn get_balance(&self) -> Result<u64> {
-        Ok(1)
+    /// Add the strings of a call to `function` of `address` without coins,
+    /// returns the arguments to give to `assembly_script_call`
+    fn call(&mut self, address: &str, function: &str) -> String {
+        let (address, function) = (self.string(address), self.string(function));
+        let param = self.string("");
+        format!(
+            "(i32.const {}) (i32.const {}) (i32.const {}) (i64.const 0)",
+            address, function, param
+        )
     }
 
-    fn get_balance_for(&self, _address: &str) -> Result<u64> {
-        Ok(1)
-    }
-
-    fn raw_set_bytecode_for(&self, address: &str, bytecode: &[u8]) -> Result<()> {
-        self.0
-            .lock()
-            .unwrap()
-            .insert(address.to_string(), bytecode.to_vec());
-        Ok(())
+    fn object(&mut self, id: u32, content: &[u8]) -> u32 {
+        let header = self.end;
+        let ptr = header + 8;
+        let bytes = [
+            &id.to_le_bytes(),
+            &(content.len() as u32).to_le_bytes(),
+            content,
+        ]
+        .concat();
+        let escaped: String = bytes.iter().map(|byte| format!("\\{:02x}", byte)).collect();
+        self.segments += &format!("(data (i32.const {}) \"{}\")\n", header, escaped);
+        self.end = (ptr + content.len() as u32 + 7) & !7;
+        ptr
     }
+}
 
-    fn raw_set_bytecode(&self, bytecode: &[u8]) -> Result<()> {
-        let address = String::from("get_string");
-        self.0.lock().unwrap().insert(address, bytecode.to_vec());
-        Ok(())
-    }
+/// Import of `assembly_script_call` as `$call`, for the modules calling others
+const CALL_IMPORT: &str =
+    r#"(import "massa" "assembly_script_call" (func $call (param i32 i32 i32 i64) (result i32)))"#;
 
-    fn print(&self, message: &str) -> Result<()> {
-        println!("{}", message);
-        self.0
-            .lock()
-            .unwrap()
-            .insert("print".into(), message.as_bytes().to_vec());
-        Ok(())
-    }
+/// Module exporting the allocation functions used by the runtime, like an
+/// AssemblyScript module, the objects of `data` and the given functions.
+fn test_module(data: &TestData, functions: &str) -> Vec<u8> {
+    format!(
+        r#"(module
+            {}
+            {}
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const {}))
+            (func (export "__new") (param $size i32) (param $id i32) (result i32)
+                (local $ptr i32)
+                (i32.store (global.get $heap) (local.get $id))
+                (i32.store offset=4 (global.get $heap) (local.get $size))
+                (local.set $ptr (i32.add (global.get $heap) (i32.const 8)))
+                (global.set $heap (i32.and
+                    (i32.add (i32.add (local.get $ptr) (local.get $size)) (i32.const 7))
+                    (i32.const -8)))
+                (local.get $ptr))
+            (func (export "__pin") (param i32) (result i32) local.get 0)
+        )"#,
+        functions, data.segments, data.end
+    )
+    .into_bytes()
+}
 
-    fn raw_get_data(&self, _: &str) -> Result<Vec<u8>> {
-        match self.0.lock().unwrap().clone().get(&"print".to_string()) {
-            Some(bytes) => Ok(bytes.clone()),
-            _ => bail!("Cannot find data"),
-        }
-    }
+/// Run a function of a module with a limit of 100_000 gas and the default
+/// gas schedule
+fn run_test_function(
+    module: &[u8],
+    function: &str,
+    interface: &dyn Interface,
+) -> Result<ExecutionResult, ExecutionError> {
+    run_function(
+        module,
+        100_000,
+        function,
+        "",
+        interface,
+        GasCosts::default(),
+    )
+}
 
-    fn get_call_coins(&self) -> Result<u64> {
-        Ok(0)
+/// Gas costs accepting the `start` function of the AssemblyScript modules
+/// created by the tests
+fn assembly_script_gas_costs() -> GasCosts {
+    GasCosts {
+        validation: ValidationLimits {
+            allow_start_function: true,
+            ..ValidationLimits::default()
+        },
+        ..GasCosts::default()
     }
+}
 
-    fn create_module(&self, module: &[u8]) -> Result<String> {
-        let address = String::from("get_string");
-        self.0
-            .lock()
-            .unwrap()
-            .insert(address.clone(), module.to_vec());
-        Ok(address)
-    }
+/// Ledger of the tests, the executions being run by `caller`
+fn test_ledger() -> MockLedger {
+    MockLedger::with_caller("caller", 1_000)
+}
 
-    fn send_message(
-        &self,
-        _target_address: &str,
-        _target_handler: &str,
-        _validity_start: (u64, u8),
-        _validity_end: (u64, u8),
-        _max_gas: u64,
-        _gas_price: u64,
-        _coins: u64,
-        _data: &[u8],
-    ) -> Result<()> {
-        Ok(())
-    }
+/// Deploy a module at the given address, creating its account if needed
+fn deploy(ledger: &MockLedger, address: &str, bytecode: &[u8]) {
+    ledger.with_state(|state| {
+        let account = state.accounts.entry(address.to_string()).or_default();
+        account.bytecode = bytecode.to_vec();
+    });
 }
 
 #[test]
-#[serial]
 fn test_caller() {
-    settings::reset_metering();
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/get_string.wat"
     ));
-    interface
-        .raw_set_bytecode_for("get_string", module.as_ref())
-        .unwrap();
+    deploy(&interface, "get_string", module);
     // test only if the module is valid
-    run_main(module, 20_000, &*interface).expect("Failed to run_main get_string.wat");
+    run_main(module, 20_000, &interface, GasCosts::default())
+        .expect("Failed to run_main get_string.wat");
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/caller.wat"
     ));
-    let a = run_main(module, 20_000, &*interface).expect("Failed to run_main caller.wat");
-    let prev_call_price = settings::metering_call();
-    settings::set_metering(0);
-    let b = run_main(module, 20_000, &*interface).expect("Failed to run_main caller.wat");
-    assert_eq!(a + prev_call_price, b);
-    let v_out = interface.raw_get_data("").unwrap();
-    let output = std::str::from_utf8(&v_out).unwrap();
-    assert_eq!(output, "hello you");
+    let gas_costs = GasCosts::default();
+    let a = run_main(module, 20_000, &interface, gas_costs.clone())
+        .expect("Failed to run_main caller.wat")
+        .remaining_gas;
+    let free_call = GasCosts {
+        call: 0,
+        ..GasCosts::default()
+    };
+    let b = run_main(module, 20_000, &interface, free_call)
+        .expect("Failed to run_main caller.wat")
+        .remaining_gas;
+    assert_eq!(a + gas_costs.call, b);
+    assert_eq!(interface.state().prints.last().unwrap(), "hello you");
 
     // Test now if we failed if metering is too hight
-    settings::set_metering(15_000);
-    run_main(module, 20_000, &*interface).expect_err("Expected to be out of operation gas");
+    let expensive_call = GasCosts {
+        call: 15_000,
+        ..GasCosts::default()
+    };
+    run_main(module, 20_000, &interface, expensive_call)
+        .expect_err("Expected to be out of operation gas");
 }
 
 #[test]
-#[serial]
 fn test_local_hello_name_caller() {
-    settings::reset_metering();
     // This test should verify that even if we failed to load a module,
     // we should never panic and just stop the call stack
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/get_string.wat"
     ));
-    interface
-        .raw_set_bytecode_for("get_string", module.as_ref())
-        .unwrap();
-    run_main(module, 100, &*interface).expect("Failed to run_main get_string.wat");
+    deploy(&interface, "get_string", module);
+    run_main(module, 10_000, &interface, GasCosts::default())
+        .expect("Failed to run_main get_string.wat");
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/local_hello_name_caller.wat"
     ));
-    run_main(module, 20_000, &*interface)
+    run_main(module, 20_000, &interface, GasCosts::default())
         .expect_err("Succeeded to run_main local_hello_name_caller.wat");
 }
 
 #[test]
-#[serial]
 fn test_module_creation() {
-    settings::reset_metering();
     // This test should create a smartcontract module and call it
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/create_sc.wasm"
     ));
-    run_main(module, 100_000, &*interface).expect("Failed to run_main create_sc.wat");
+    run_main(module, 100_000, &interface, assembly_script_gas_costs())
+        .expect("Failed to run_main create_sc.wat");
+    // caller.wat calls the created module at the address "get_string"
+    let created = interface.get_owned_addresses().unwrap();
+    deploy(
+        &interface,
+        "get_string",
+        &interface.get_module(&created[0]).unwrap(),
+    );
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/caller.wat"
     ));
-    run_main(module, 20_000, &*interface).expect("Failed to run_main caller.wat");
+    run_main(module, 20_000, &interface, GasCosts::default())
+        .expect("Failed to run_main caller.wat");
 }
 
 #[test]
-#[serial]
 fn test_not_enough_gas_error() {
-    settings::reset_metering();
     // This test should create a smartcontract module and call it
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/create_sc.wasm"
     ));
-    run_main(module, 100_000, &*interface).expect("Failed to run_main create_sc.wat");
+    run_main(module, 100_000, &interface, assembly_script_gas_costs())
+        .expect("Failed to run_main create_sc.wat");
+    // caller.wat calls the created module at the address "get_string"
+    let created = interface.get_owned_addresses().unwrap();
+    deploy(
+        &interface,
+        "get_string",
+        &interface.get_module(&created[0]).unwrap(),
+    );
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/caller.wat"
     ));
-    match run_main(module, 10000, &*interface) {
+    match run_main(module, 10000, &interface, GasCosts::default()) {
         Ok(_) => panic!("Shouldn't pass successfully =-("),
         Err(err) => {
-            assert!(err
-                .to_string()
-                .starts_with("RuntimeError: Not enough gas, limit reached at:"))
+            assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+            assert_eq!(err.remaining_gas(), 0);
         }
     }
 }
 
 #[test]
-#[serial]
 fn test_send_message() {
-    settings::reset_metering();
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/send_message.wasm"
     ));
-    run_main(module, 100_000, &*interface).expect("Failed to run_main send_message.wat");
+    run_main(module, 100_000, &interface, assembly_script_gas_costs())
+        .expect("Failed to run_main send_message.wat");
+    let messages = interface.state().messages;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].target_handler, "receive");
+    assert_eq!(interface.get_balance_for("caller").unwrap(), 900);
 }
 
 #[test]
-#[serial]
 fn test_run_function() {
-    settings::reset_metering();
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/receive_message.wasm"
     ));
-    run_function(module, 100_000, "receive", "data", &*interface)
-        .expect("Failed to run_function receive_message.wat");
+    run_function(
+        module,
+        100_000,
+        "receive",
+        "data",
+        &interface,
+        GasCosts::default(),
+    )
+    .expect("Failed to run_function receive_message.wat");
 }
 
 #[test]
-#[serial]
 fn test_run_main_without_main() {
-    settings::reset_metering();
-    let interface: Box<dyn Interface> =
-        Box::new(TestInterface(Arc::new(Mutex::new(Ledger::new()))));
+    let interface = test_ledger();
     let module = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/wasm/build/no_main.wasm"
     ));
-    run_main(module, 100_000, &*interface).expect_err("An error should spawn here");
+    run_main(module, 100_000, &interface, GasCosts::default())
+        .expect_err("An error should spawn here");
+}
+
+#[test]
+fn test_module_cache_eviction() {
+    let config = CompilationConfig::new(&GasCosts::default());
+    let bytecodes: Vec<String> = (0..3)
+        .map(|i| format!("(module (func (export \"f{}\")))", i))
+        .collect();
+    let keys: Vec<ModuleKey> = bytecodes
+        .iter()
+        .map(|bytecode| ModuleKey::new(bytecode.as_bytes(), &config))
+        .collect();
+    let modules: Vec<_> = bytecodes
+        .iter()
+        .map(|bytecode| compile_module(bytecode.as_bytes(), &config).unwrap())
+        .collect();
+
+    // The least recently used module is evicted
+    let mut cache = ModuleCache::new(ModuleCacheConfig {
+        max_entries: 2,
+        eviction_policy: EvictionPolicy::LeastRecentlyUsed,
+    });
+    cache.insert(keys[0].clone(), modules[0].clone());
+    cache.insert(keys[1].clone(), modules[1].clone());
+    assert!(cache.get(&keys[0]).is_some());
+    cache.insert(keys[2].clone(), modules[2].clone());
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(&keys[0]).is_some());
+    assert!(cache.get(&keys[1]).is_none());
+    assert!(cache.get(&keys[2]).is_some());
+
+    // The first compiled module is evicted, even if it has just been used
+    let mut cache = ModuleCache::new(ModuleCacheConfig {
+        max_entries: 2,
+        eviction_policy: EvictionPolicy::FirstInFirstOut,
+    });
+    cache.insert(keys[0].clone(), modules[0].clone());
+    cache.insert(keys[1].clone(), modules[1].clone());
+    assert!(cache.get(&keys[0]).is_some());
+    cache.insert(keys[2].clone(), modules[2].clone());
+    assert!(cache.get(&keys[0]).is_none());
+    assert!(cache.get(&keys[1]).is_some());
+
+    // A module compiled with another configuration is another entry
+    let other_config = CompilationConfig {
+        max_number_of_pages: 32,
+        ..config.clone()
+    };
+    assert!(cache
+        .get(&ModuleKey::new(bytecodes[1].as_bytes(), &other_config))
+        .is_none());
+
+    // The fingerprint of a configuration doesn't depend on the compiler or on
+    // the host
+    assert_ne!(config.fingerprint(), other_config.fingerprint());
+    assert_eq!(
+        blake3::Hash::from(config.fingerprint()).to_hex().as_str(),
+        "c5a119dc7b650cd8366ec0807e771fd2b9c5749d4b24248785017787cef9cd1b"
+    );
+
+    // A zero sized cache keeps nothing
+    cache.set_config(ModuleCacheConfig {
+        max_entries: 0,
+        eviction_policy: EvictionPolicy::FirstInFirstOut,
+    });
+    assert_eq!(cache.len(), 0);
+    cache.insert(keys[0].clone(), modules[0].clone());
+    assert_eq!(cache.len(), 0);
+}
+
+#[test]
+#[serial]
+fn test_cached_module_start_is_metered() {
+    let interface = test_ledger();
+    // get_string has a start function but no main, running it only executes
+    // the start function
+    let module = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/wasm/build/get_string.wat"
+    ));
+    // A schedule used by no other test, so that the module has its own entry
+    // in the cache
+    let gas_costs = GasCosts {
+        max_stack_height: 10_000,
+        ..GasCosts::default()
+    };
+    let key = ModuleKey::new(module, &CompilationConfig::new(&gas_costs));
+    clear_module_cache();
+    let stats = module_cache::stats(&key);
+    let remaining = run_main(module, 20_000, &interface, gas_costs.clone())
+        .expect("Failed to run get_string.wat")
+        .remaining_gas;
+    assert_eq!(
+        module_cache::stats(&key),
+        CacheStats {
+            compilations: stats.compilations + 1,
+            ..stats
+        }
+    );
+    // The second run uses the cached module, and the start function is still
+    // charged on the limit of the execution
+    let remaining_cached = run_main(module, 30_000, &interface, gas_costs.clone())
+        .expect("Failed to run get_string.wat")
+        .remaining_gas;
+    assert_eq!(
+        module_cache::stats(&key),
+        CacheStats {
+            hits: stats.hits + 1,
+            compilations: stats.compilations + 1,
+            ..stats
+        }
+    );
+    assert_eq!(remaining + 10_000, remaining_cached);
+    run_main(module, 0, &interface, gas_costs)
+        .expect_err("The start function should run out of gas");
+}
+
+#[test]
+#[serial]
+fn test_precompiled_module() {
+    let interface = test_ledger();
+    let module = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/wasm/build/get_string.wat"
+    ));
+    let gas_costs = GasCosts {
+        max_stack_height: 10_001,
+        ..GasCosts::default()
+    };
+    let key = ModuleKey::new(module, &CompilationConfig::new(&gas_costs));
+    let artifact =
+        precompile_module(module, &gas_costs).expect("Failed to precompile get_string.wat");
+    let remaining = run_main(module, 20_000, &interface, gas_costs.clone())
+        .expect("Failed to run get_string.wat")
+        .remaining_gas;
+
+    // Simulate a restart of the node, the module is loaded from the artifact
+    // and isn't compiled again
+    clear_module_cache();
+    let stats = module_cache::stats(&key);
+    load_precompiled_module(module, &artifact, &gas_costs)
+        .expect("Failed to load the precompiled module");
+    let remaining_loaded = run_main(module, 20_000, &interface, gas_costs.clone())
+        .expect("Failed to run get_string.wat")
+        .remaining_gas;
+    assert_eq!(
+        module_cache::stats(&key),
+        CacheStats {
+            hits: stats.hits + 1,
+            loads: stats.loads + 1,
+            ..stats
+        }
+    );
+    assert_eq!(remaining, remaining_loaded);
+
+    // The artifact of another bytecode is rejected
+    load_precompiled_module(b"(module)", &artifact, &gas_costs)
+        .expect_err("The artifact of another bytecode should be rejected");
+
+    // Artifacts of another format, runtime version or wasmer version are
+    // rejected, even if nothing else changed
+    let mut other_format = artifact.clone();
+    other_format[8..12].copy_from_slice(&(ARTIFACT_VERSION + 1).to_le_bytes());
+    let runtime_version = 13;
+    let wasmer_version = runtime_version + env!("CARGO_PKG_VERSION").len() + 1;
+    assert_eq!(
+        &artifact[wasmer_version..][..wasmer::VERSION.len()],
+        wasmer::VERSION.as_bytes()
+    );
+    let mut other_runtime = artifact.clone();
+    other_runtime[runtime_version] ^= 1;
+    let mut other_wasmer = artifact.clone();
+    other_wasmer[wasmer_version] ^= 1;
+    for (stale, version) in [
+        (other_format, "artifact format"),
+        (other_runtime, "version of the runtime"),
+        (other_wasmer, "version of wasmer"),
+    ] {
+        let err = load_precompiled_module(module, &stale, &gas_costs)
+            .expect_err("A stale artifact should be rejected");
+        assert!(err.to_string().contains(version), "{}", err);
+    }
+
+    // Artifacts compiled for another memory limit are rejected
+    let other_pages = GasCosts {
+        max_number_of_pages: 32,
+        ..gas_costs.clone()
+    };
+    load_precompiled_module(module, &artifact, &other_pages)
+        .expect_err("An artifact of another configuration should be rejected");
+
+    // Corrupted and truncated artifacts are rejected
+    let mut corrupted = artifact.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    load_precompiled_module(module, &corrupted, &gas_costs)
+        .expect_err("A corrupted artifact should be rejected");
+    load_precompiled_module(module, &artifact[..artifact.len() / 2], &gas_costs)
+        .expect_err("A truncated artifact should be rejected");
+    load_precompiled_module(module, module, &gas_costs).expect_err("A bytecode isn't an artifact");
+}
+
+#[test]
+fn test_concurrent_gas_costs() {
+    let interface = test_ledger();
+    let module = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/wasm/build/get_string.wat"
+    ));
+    deploy(&interface, "get_string", module);
+    let module = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/wasm/build/caller.wat"
+    ));
+    // Executions with different gas schedules don't interfere with each other
+    let handles: Vec<_> = [0, 100, 200, 300]
+        .into_iter()
+        .map(|call| {
+            let interface = interface.clone();
+            std::thread::spawn(move || {
+                let gas_costs = GasCosts {
+                    call,
+                    ..GasCosts::default()
+                };
+                let remaining = (0..5)
+                    .map(|_| {
+                        run_main(module, 100_000, &interface, gas_costs.clone())
+                            .expect("Failed to run_main caller.wat")
+                            .remaining_gas
+                    })
+                    .collect::<Vec<_>>();
+                (call, remaining)
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    let (_, free_remaining) = &results[0];
+    for (call, remaining) in &results {
+        for (free, remaining) in free_remaining.iter().zip(remaining) {
+            assert_eq!(free - call, *remaining);
+        }
+    }
+}
+
+#[test]
+fn test_gas_schedule_loading() {
+    let schedule = GasSchedule {
+        version: 3,
+        costs: GasCosts {
+            call: 500,
+            ..GasCosts::default()
+        },
+    };
+    let json = serde_json::to_string(&schedule).unwrap();
+    assert_eq!(GasSchedule::from_json(&json).unwrap(), schedule);
+    let toml = toml::to_string(&schedule).unwrap();
+    assert_eq!(GasSchedule::from_toml(&toml).unwrap(), schedule);
+
+    let path = std::env::temp_dir().join(format!("gas_schedule_{}.toml", std::process::id()));
+    std::fs::write(&path, &toml).unwrap();
+    let loaded = GasSchedule::from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap(), schedule);
+
+    // Every cost must be given
+    let mut value = serde_json::to_value(&schedule).unwrap();
+    value["costs"]
+        .as_object_mut()
+        .unwrap()
+        .remove("hash_per_byte");
+    GasSchedule::from_json(&value.to_string()).expect_err("A missing cost should be rejected");
+    let mut value = serde_json::to_value(&schedule).unwrap();
+    value.as_object_mut().unwrap().remove("version");
+    GasSchedule::from_json(&value.to_string()).expect_err("A missing version should be rejected");
+
+    // Unknown costs are rejected, they are probably a typo
+    let mut value = serde_json::to_value(&schedule).unwrap();
+    value["costs"]["cal"] = 200.into();
+    GasSchedule::from_json(&value.to_string()).expect_err("An unknown cost should be rejected");
+    let toml = format!("{}\nunknown = 1\n", toml);
+    GasSchedule::from_toml(&toml).expect_err("An unknown cost should be rejected");
+
+    // The values are validated
+    let mut value = serde_json::to_value(&schedule).unwrap();
+    value["costs"]["max_number_of_pages"] = 0.into();
+    GasSchedule::from_json(&value.to_string()).expect_err("A memory of 0 pages should be rejected");
+}
+
+#[test]
+fn test_operator_costs() {
+    let interface = test_ledger();
+    let module = test_module(
+        &TestData::default(),
+        r#"(func (export "grow") (param i32)
+            (drop (memory.grow (i32.const 1)))
+            (drop (i32.div_u (i32.const 4) (i32.const 2))))"#,
+    );
+    let run = |gas_costs: GasCosts| {
+        run_function(&module, 100_000, "grow", "", &interface, gas_costs)
+            .expect("Failed to run grow")
+            .remaining_gas
+    };
+    let flat = run(GasCosts::default());
+    // Each class has its own cost, and the modules compiled for another cost
+    // aren't reused
+    let expensive_grow = run(GasCosts {
+        operators: OperatorCosts {
+            memory_grow: 1_000,
+            ..OperatorCosts::default()
+        },
+        ..GasCosts::default()
+    });
+    assert_eq!(flat - 999, expensive_grow);
+    let expensive_division = run(GasCosts {
+        operators: OperatorCosts {
+            division: 50,
+            ..OperatorCosts::default()
+        },
+        ..GasCosts::default()
+    });
+    assert_eq!(flat - 49, expensive_division);
+}
+
+#[test]
+fn test_reject_floats() {
+    let interface = test_ledger();
+    let module = test_module(
+        &TestData::default(),
+        r#"(func (export "half") (param i32)
+            (drop (f64.div (f64.convert_i32_s (local.get 0)) (f64.const 2))))
+        (func (export "noop") (param i32))"#,
+    );
+    let run = |function: &str, reject_floats: bool| {
+        let gas_costs = GasCosts {
+            reject_floats,
+            ..GasCosts::default()
+        };
+        run_function(&module, 100_000, function, "", &interface, gas_costs)
+    };
+    run("half", false).expect("Floats are allowed by default");
+    // The whole module is rejected, even the functions without floats
+    let err = run("noop", true).expect_err("The module uses floats");
+    assert!(matches!(err, ExecutionError::InvalidBytecode { .. }));
+}
+
+#[test]
+fn test_memory_pages() {
+    let ledger = test_ledger();
+    let mut data = TestData::default();
+    let call_noop = data.call("caller", "noop");
+    let module = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (func (export "noop") (param i32))
+        (func (export "grow") (param i32)
+            (if (i32.eq (memory.grow (i32.const 3)) (i32.const -1))
+                (then unreachable)))
+        (func (export "grow_too_much") (param i32)
+            (if (i32.ne (memory.grow (i32.const 10)) (i32.const -1))
+                (then unreachable)))
+        (func (export "grow_and_call") (param i32)
+            (drop (memory.grow (i32.const 2)))
+            (drop (call $call {call_noop})))"#
+        ),
+    );
+    deploy(&ledger, "caller", &module);
+    let run = |function: &str, limit: u64, memory_page: u64, max_number_of_pages: u32| {
+        let gas_costs = GasCosts {
+            memory_page,
+            max_number_of_pages,
+            ..GasCosts::default()
+        };
+        run_function(&module, limit, function, "", &ledger, gas_costs)
+    };
+    let gas_used = |function: &str, memory_page: u64| {
+        run(function, 100_000, memory_page, 64)
+            .expect("Failed to run the function")
+            .gas_used
+    };
+
+    // The initial page is charged, then each page allocated
+    assert_eq!(gas_used("noop", 1_000) - gas_used("noop", 0), 1_000);
+    assert_eq!(gas_used("grow", 1_000) - gas_used("grow", 0), 4_000);
+    let err = run("noop", 100_000, 200_000, 64).expect_err("The initial page is too expensive");
+    assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+    let err = run("noop", 100_000, u64::MAX, 64).expect_err("The page cost overflows");
+    assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+    let err = run("grow", 100_000, 30_000, 64).expect_err("The pages are too expensive");
+    assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+    // The cost of the pages saturates instead of wrapping to 2 gas
+    let err = run("grow", u64::MAX, u64::MAX / 3 + 1, 64).expect_err("The pages are too expensive");
+    assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+    run("grow_too_much", 100_000, 1_000, 4).expect("memory.grow should fail");
+
+    // The pages of the callers count in the limit
+    run("grow_and_call", 100_000, 1_000, 4).expect("The callee memory should fit");
+    let err = run("grow_and_call", 100_000, 1_000, 3).expect_err("The callee memory can't fit");
+    assert!(matches!(err, ExecutionError::MemoryLimit { .. }));
+}
+
+#[test]
+fn test_execution_errors() {
+    let interface = test_ledger();
+    let mut data = TestData::default();
+    let empty = data.string("");
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(import "env" "abort" (func $abort (param i32 i32 i32 i32)))
+        (import "massa" "assembly_script_get_data" (func $get_data (param i32) (result i32)))
+        (func (export "abort") (param i32)
+            (call $abort (i32.const {empty}) (i32.const {empty}) (i32.const 4) (i32.const 2)))
+        (func (export "get_data") (param i32)
+            (drop (call $get_data (i32.const {empty}))))
+        (func (export "trap") (param i32)
+            unreachable)
+        (func (export "loop") (param i32)
+            (loop (br 0)))"#
+        ),
+    );
+    let run = |function: &str| {
+        run_test_function(&module, function, &interface).expect_err("The execution should fail")
+    };
+
+    match run("abort") {
+        ExecutionError::Abort {
+            function,
+            line,
+            col,
+            remaining_gas,
+            ..
+        } => {
+            assert_eq!(function, "abort");
+            assert_eq!((line, col), (4, 2));
+            assert!(remaining_gas > 0);
+        }
+        err => panic!("Unexpected error {:?}", err),
+    }
+    // The abort is given to the interface
+    assert_eq!(interface.state().aborts, vec![" at :4:2".to_string()]);
+    // The interface fails because there is no data
+    assert!(matches!(run("get_data"), ExecutionError::Interface { .. }));
+    assert!(matches!(run("trap"), ExecutionError::Trap { .. }));
+    let err = run("loop");
+    assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+    assert_eq!(err.function(), "loop");
+    assert!(matches!(
+        run("missing"),
+        ExecutionError::MissingExport { .. }
+    ));
+
+    let err = run_main(b"not wasm", 100, &interface, GasCosts::default())
+        .expect_err("The bytecode is invalid");
+    assert!(matches!(err, ExecutionError::InvalidBytecode { .. }));
+    assert_eq!(err.remaining_gas(), 100);
+    let module = br#"(module (memory (export "memory") 100))"#;
+    let err = run_main(module, 100, &interface, GasCosts::default())
+        .expect_err("The memory is larger than the limit");
+    assert!(matches!(err, ExecutionError::MemoryLimit { .. }));
+}
+
+#[test]
+fn test_return_values() {
+    let interface = test_ledger();
+    let mut data = TestData::default();
+    let (hi, bytes) = (data.string("hi"), data.bytes(&[1, 2, 3]));
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(func (export "string") (param i32) (result i32) i32.const {hi})
+        (func (export "bytes") (param i32) (result i32) i32.const {bytes})
+        (func (export "int") (param i32) (result i64) i64.const -7)
+        (func (export "empty") (param i32))
+        (func (export "main") (param i32) (result i32) i32.const 3)"#
+        ),
+    );
+    let run = |function: &str| {
+        run_test_function(&module, function, &interface).expect("Failed to run the function")
+    };
+    assert_eq!(run("string").ret, ReturnValue::String("hi".to_string()));
+    assert_eq!(run("bytes").ret, ReturnValue::Bytes(vec![1, 2, 3]));
+    assert_eq!(run("int").ret, ReturnValue::Int(-7));
+    let ExecutionResult {
+        ret,
+        gas_used,
+        remaining_gas,
+        ..
+    } = run("empty");
+    assert_eq!(ret, ReturnValue::Empty);
+    assert!(gas_used > 0);
+    assert_eq!(gas_used + remaining_gas, 100_000);
+    let result =
+        run_main(&module, 100_000, &interface, GasCosts::default()).expect("Failed to run main");
+    assert_eq!(result.ret, ReturnValue::Int(3));
+}
+
+#[test]
+fn test_datastore_bytes() {
+    let interface = test_ledger();
+    // The value isn't valid UTF-8
+    let mut data = TestData::default();
+    let (key, value) = (data.string("k"), data.bytes(&[0xff, 0xfe, 0]));
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (import "massa" "assembly_script_append_data_bytes" (func $append (param i32 i32)))
+        (import "massa" "assembly_script_get_data_bytes" (func $get (param i32) (result i32)))
+        (func (export "set") (param i32)
+            (call $set (i32.const {key}) (i32.const {value})))
+        (func (export "append") (param i32) (result i32)
+            (call $append (i32.const {key}) (i32.const {value}))
+            (call $get (i32.const {key})))"#
+        ),
+    );
+    let run = |function: &str| {
+        run_test_function(&module, function, &interface).expect("Failed to run the function")
+    };
+    let set = run("set");
+    assert_eq!(interface.raw_get_data("k").unwrap(), vec![0xff, 0xfe, 0]);
+    let append = run("append");
+    assert_eq!(
+        append.ret,
+        ReturnValue::Bytes(vec![0xff, 0xfe, 0, 0xff, 0xfe, 0])
+    );
+
+    // The values are charged per byte
+    let expensive_bytes = GasCosts {
+        set_data_value_mult: 11,
+        ..GasCosts::default()
+    };
+    let expensive_set = run_function(&module, 100_000, "set", "", &interface, expensive_bytes)
+        .expect("Failed to run the function");
+    assert_eq!(set.remaining_gas - 3 * 10, expensive_set.remaining_gas);
+}
+
+#[test]
+fn test_get_keys() {
+    let interface = test_ledger();
+    for key in ["holder_a", "holder_b", "holder_c", "other"] {
+        interface.raw_set_data(key, b"1").unwrap();
+    }
+    let mut data = TestData::default();
+    let prefix = data.string("holder_");
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_get_keys" (func $get_keys (param i32 i32 i32) (result i32)))
+        (func (export "page") (param i32) (result i32)
+            (call $get_keys (i32.const {prefix}) (i32.const 1) (i32.const 5)))
+        (func (export "first") (param i32) (result i32)
+            (call $get_keys (i32.const {prefix}) (i32.const 0) (i32.const 1)))
+        (func (export "negative") (param i32) (result i32)
+            (call $get_keys (i32.const {prefix}) (i32.const -1) (i32.const 1)))"#
+        ),
+    );
+    let run = |function: &str, gas_costs: GasCosts| {
+        run_function(&module, 100_000, function, "", &interface, gas_costs)
+    };
+    let page = run("page", GasCosts::default()).expect("Failed to get the keys");
+    assert_eq!(
+        page.ret,
+        ReturnValue::String(r#"["holder_b","holder_c"]"#.to_string())
+    );
+    let first = run("first", GasCosts::default()).expect("Failed to get the keys");
+    assert_eq!(
+        first.ret,
+        ReturnValue::String(r#"["holder_a"]"#.to_string())
+    );
+    run("negative", GasCosts::default()).expect_err("A negative offset should fail");
+
+    // Each key with the prefix is charged, even the ones before and after
+    // the page
+    let expensive_keys = GasCosts {
+        get_keys_per_key: 110,
+        ..GasCosts::default()
+    };
+    let expensive_page = run("page", expensive_keys.clone()).expect("Failed to get the keys");
+    assert_eq!(page.remaining_gas - 3 * 100, expensive_page.remaining_gas);
+    let expensive_first = run("first", expensive_keys).expect("Failed to get the keys");
+    assert_eq!(first.remaining_gas - 3 * 100, expensive_first.remaining_gas);
+}
+
+#[test]
+fn test_journaled_interface() {
+    let ledger = test_ledger();
+    // Sets "k" to "after", then traps in `fail`
+    let mut data = TestData::default();
+    let (key, value) = (data.string("k"), data.bytes(b"after"));
+    let callee = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (func (export "pass") (param i32)
+            (call $set (i32.const {key}) (i32.const {value})))
+        (func (export "fail") (param i32)
+            (call $set (i32.const {key}) (i32.const {value}))
+            unreachable)"#
+        ),
+    );
+    deploy(&ledger, "callee", &callee);
+    ledger.raw_set_data_for("callee", "k", b"before").unwrap();
+    let mut data = TestData::default();
+    let (call_pass, call_fail) = (data.call("callee", "pass"), data.call("callee", "fail"));
+    let caller = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (func (export "pass") (param i32)
+            (drop (call $call {call_pass})))
+        (func (export "fail") (param i32)
+            (drop (call $call {call_fail})))"#
+        ),
+    );
+    let journaled = JournaledInterface::new(Box::new(ledger.clone()));
+    let run = |function: &str| run_test_function(&caller, function, &journaled);
+
+    // The write of the failed call is reverted, and its error forwarded
+    let err = run("fail").expect_err("The nested call should fail");
+    assert!(matches!(err, ExecutionError::Trap { .. }));
+    assert_eq!(ledger.raw_get_data_for("callee", "k").unwrap(), b"before");
+
+    // The write of a successful call is kept, until the whole execution is
+    // rolled back
+    run("pass").expect("Failed to call the module");
+    assert_eq!(ledger.raw_get_data_for("callee", "k").unwrap(), b"after");
+    journaled.rollback().expect("Failed to roll back");
+    assert_eq!(ledger.raw_get_data_for("callee", "k").unwrap(), b"before");
+
+    // A call whose changes can't be undone is still finished in the wrapped
+    // interface
+    journaled.init_call("callee", 0).unwrap();
+    journaled.raw_set_data("k", b"after").unwrap();
+    ledger.with_state(|state| {
+        state.accounts.remove("callee");
+    });
+    journaled
+        .revert_call()
+        .expect_err("The account of the change doesn't exist anymore");
+    assert_eq!(ledger.get_call_stack().unwrap(), vec!["caller".to_string()]);
+}
+
+#[test]
+fn test_try_call() {
+    let ledger = test_ledger();
+    // Sets "k" to "after", then traps in `fail`, or appends to the missing
+    // key "m" in `append`, or runs until it has no gas in `burn`
+    let mut data = TestData::default();
+    let (key, missing, value) = (data.string("k"), data.string("m"), data.bytes(b"after"));
+    let callee = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (import "massa" "assembly_script_append_data_bytes" (func $append (param i32 i32)))
+        (func (export "pass") (param i32) (result i32)
+            (call $set (i32.const {key}) (i32.const {value}))
+            (i32.const {value}))
+        (func (export "fail") (param i32)
+            (call $set (i32.const {key}) (i32.const {value}))
+            unreachable)
+        (func (export "append") (param i32)
+            (call $set (i32.const {key}) (i32.const {value}))
+            (call $append (i32.const {missing}) (i32.const {value})))
+        (func (export "burn") (param i32)
+            (loop br 0))"#
+        ),
+    );
+    deploy(&ledger, "callee", &callee);
+    ledger.raw_set_data_for("callee", "k", b"before").unwrap();
+    // Each function checks the returned code and returns the pointer written
+    // in `result`
+    let mut data = TestData::default();
+    let (pass, fail) = (data.call("callee", "pass"), data.call("callee", "fail"));
+    let (append, burn) = (data.call("callee", "append"), data.call("callee", "burn"));
+    let result = data.bytes(&[0; 4]);
+    let caller = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_try_call" (func $try_call (param i32 i32 i32 i64 i32) (result i32)))
+        (func (export "pass") (param i32) (result i32)
+            (if (i32.ne (call $try_call {pass} (i32.const {result})) (i32.const 0))
+                (then unreachable))
+            (i32.load (i32.const {result})))
+        (func (export "fail") (param i32) (result i32)
+            (if (i32.ne (call $try_call {fail} (i32.const {result})) (i32.const 7))
+                (then unreachable))
+            (i32.load (i32.const {result})))
+        (func (export "append") (param i32) (result i32)
+            (if (i32.ne (call $try_call {append} (i32.const {result})) (i32.const 3))
+                (then unreachable))
+            (i32.load (i32.const {result})))
+        (func (export "burn") (param i32) (result i32)
+            (if (i32.ne (call $try_call {burn} (i32.const {result})) (i32.const 1))
+                (then unreachable))
+            (i32.load (i32.const {result})))"#
+        ),
+    );
+    let journaled = JournaledInterface::new(Box::new(ledger.clone()));
+    let run = |function: &str| run_test_function(&caller, function, &journaled);
+
+    // The caller gets the error message and goes on, the write is reverted
+    let result = run("fail").expect("The failure of the callee should be caught");
+    match result.ret {
+        ReturnValue::String(message) => assert!(message.starts_with("Trap in fail")),
+        ret => panic!("Unexpected return value {:?}", ret),
+    }
+    assert_eq!(ledger.raw_get_data_for("callee", "k").unwrap(), b"before");
+    // Only the changes that succeeded are reverted
+    let result = run("append").expect("The failure of the interface should be caught");
+    assert!(matches!(result.ret, ReturnValue::String(message) if message.contains("no data")));
+    assert_eq!(ledger.raw_get_data_for("callee", "k").unwrap(), b"before");
+    assert!(!ledger.has_data_for("callee", "m").unwrap());
+    // The callee can't use the gas reserved for the caller
+    let result = run("burn").expect("The caller should have gas left to catch the failure");
+    assert!(matches!(result.ret, ReturnValue::String(message) if message.contains("burn")));
+    assert!(result.remaining_gas > 0);
+
+    let result = run("pass").expect("Failed to call the module");
+    assert_eq!(result.ret, ReturnValue::Bytes(b"after".to_vec()));
+    assert_eq!(ledger.raw_get_data_for("callee", "k").unwrap(), b"after");
+}
+
+#[test]
+fn test_call_with_gas() {
+    let ledger = test_ledger();
+    // Runs until it has no gas
+    let callee = test_module(
+        &TestData::default(),
+        r#"(func (export "burn") (param i32) (loop br 0))"#,
+    );
+    deploy(&ledger, "callee", &callee);
+    let mut data = TestData::default();
+    let (burn, result) = (data.call("callee", "burn"), data.bytes(&[0; 4]));
+    let caller = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_try_call_with_gas" (func $try_call (param i32 i32 i32 i64 i64 i32) (result i32)))
+        (import "massa" "assembly_script_call_with_gas" (func $call (param i32 i32 i32 i64 i64) (result i32)))
+        (func (export "try_1000") (param i32)
+            (if (i32.ne (call $try_call {burn} (i64.const 1000) (i32.const {result})) (i32.const 1))
+                (then unreachable)))
+        (func (export "try_2000") (param i32)
+            (if (i32.ne (call $try_call {burn} (i64.const 2000) (i32.const {result})) (i32.const 1))
+                (then unreachable)))
+        (func (export "call_1000") (param i32)
+            (drop (call $call {burn} (i64.const 1000))))"#
+        ),
+    );
+    let run = |function: &str| run_test_function(&caller, function, &ledger);
+
+    // Only the gas given to the callee is lost
+    let try_1000 = run("try_1000").expect("The failure of the callee should be caught");
+    let try_2000 = run("try_2000").expect("The failure of the callee should be caught");
+    assert_eq!(try_1000.gas_used + 1000, try_2000.gas_used);
+    assert!(try_2000.remaining_gas > 90_000);
+
+    // Without a catch, the failure is forwarded
+    let err = run("call_1000").expect_err("The failure of the callee should be forwarded");
+    assert!(matches!(err, ExecutionError::OutOfGas { .. }));
+}
+
+#[test]
+fn test_call_depth_and_reentrancy() {
+    let ledger = test_ledger();
+    // Calls itself, deployed at "caller" which runs the execution
+    let mut data = TestData::default();
+    let (recurse, noop) = (data.call("caller", "recurse"), data.call("caller", "noop"));
+    let module = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (import "massa" "assembly_script_call_non_reentrant" (func $call_non_reentrant (param i32 i32 i32 i64) (result i32)))
+        (func (export "recurse") (param i32)
+            (drop (call $call {recurse})))
+        (func (export "reenter") (param i32)
+            (drop (call $call_non_reentrant {noop})))
+        (func (export "noop") (param i32))"#
+        ),
+    );
+    deploy(&ledger, "caller", &module);
+    let gas_costs = GasCosts {
+        max_call_depth: 3,
+        ..GasCosts::default()
+    };
+    let run =
+        |function: &str| run_function(&module, 1_000_000, function, "", &ledger, gas_costs.clone());
+
+    let err = run("recurse").expect_err("The recursion should be stopped");
+    assert!(matches!(
+        err,
+        ExecutionError::CallDepth {
+            max_call_depth: 3,
+            ..
+        }
+    ));
+    let err = run("reenter").expect_err("The reentrant call should fail");
+    assert!(matches!(err, ExecutionError::Reentrancy { address, .. } if address == "caller"));
+
+    // The policy of the called module applies to every call, through a
+    // JournaledInterface too
+    ledger.with_state(|state| {
+        let account = state.accounts.get_mut("caller").unwrap();
+        account.reentrancy_policy = ReentrancyPolicy::Deny;
+    });
+    let journaled = JournaledInterface::new(Box::new(ledger.clone()));
+    let err = run_function(&module, 1_000_000, "recurse", "", &journaled, gas_costs)
+        .expect_err("The module isn't reentrant");
+    assert!(matches!(err, ExecutionError::Reentrancy { address, .. } if address == "caller"));
+}
+
+#[test]
+fn test_stack_height() {
+    let ledger = test_ledger();
+    // Recurses 20 times, then calls `recurse` if `$nested` is set. The frame
+    // of `$recurse` costs 6: 4 operands, the arguments of `$call`, and 2 for
+    // the activation, so `recurse` reaches a height of 21 * 6 = 126.
+    let mut data = TestData::default();
+    let recurse = data.call("caller", "recurse");
+    let module = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (func $recurse (param $n i32) (param $nested i32)
+            (if (local.get $n)
+                (then (call $recurse (i32.sub (local.get $n) (i32.const 1)) (local.get $nested)))
+                (else (if (local.get $nested)
+                    (then (drop (call $call {recurse})))))))
+        (func $locals (param $n i32) (local {locals})
+            (if (local.get $n)
+                (then (call $locals (i32.sub (local.get $n) (i32.const 1))))))
+        (func (export "recurse") (param i32)
+            (call $recurse (i32.const 20) (i32.const 0)))
+        (func (export "recurse_nested") (param i32)
+            (call $recurse (i32.const 20) (i32.const 1)))
+        (func (export "recurse_locals") (param i32)
+            (call $locals (i32.const 5)))"#,
+            locals = "i64 ".repeat(100)
+        ),
+    );
+    deploy(&ledger, "caller", &module);
+    let run = |function: &str, max_stack_height: u32| {
+        let gas_costs = GasCosts {
+            max_stack_height,
+            ..GasCosts::default()
+        };
+        run_function(&module, 1_000_000, function, "", &ledger, gas_costs)
+    };
+
+    run("recurse", 126).expect("The recursion is below the limit");
+    run("recurse", u32::MAX).expect("A limit above i32::MAX is still unsigned");
+    let err = run("recurse", 125).expect_err("The recursion should trap");
+    assert!(matches!(err, ExecutionError::Trap { .. }));
+    // The called module starts from the height of its caller, 126 plus 2 for
+    // the call of the imported function
+    let err = run("recurse_nested", 253).expect_err("The recursion should trap");
+    assert!(matches!(err, ExecutionError::Trap { .. }));
+    run("recurse_nested", 254).expect("The recursion is below the limit");
+    // The frame of `$locals` costs 100 locals, 2 operands and 2 for the
+    // activation: 6 nested calls reach 624, although they're fewer than the
+    // calls of `recurse`
+    let err = run("recurse_locals", 623).expect_err("The recursion should trap");
+    assert!(matches!(err, ExecutionError::Trap { .. }));
+    run("recurse_locals", 624).expect("The recursion is below the limit");
+}
+
+#[test]
+fn test_read_only() {
+    let ledger = test_ledger();
+    ledger.raw_set_data("k", b"before").unwrap();
+    let mut data = TestData::default();
+    let (key, value) = (data.string("k"), data.bytes(b"after"));
+    let callee = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (import "massa" "assembly_script_get_data_bytes" (func $get (param i32) (result i32)))
+        (func (export "get") (param i32) (result i32)
+            (call $get (i32.const {key})))
+        (func (export "set") (param i32)
+            (call $set (i32.const {key}) (i32.const {value})))"#
+        ),
+    );
+    deploy(&ledger, "callee", &callee);
+    let mut data = TestData::default();
+    let (address, set, empty) = (data.string("callee"), data.string("set"), data.string(""));
+    let caller = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_static_call" (func $static_call (param i32 i32 i32) (result i32)))
+        (func (export "static_set") (param i32)
+            (drop (call $static_call (i32.const {address}) (i32.const {set}) (i32.const {empty}))))"#
+        ),
+    );
+
+    // Reading is allowed, writing fails
+    let result = run_function_readonly(&callee, 100_000, "get", "", &ledger, GasCosts::default())
+        .expect("Failed to read in a read-only execution");
+    assert_eq!(result.ret, ReturnValue::Bytes(b"before".to_vec()));
+    let err = run_function_readonly(&callee, 100_000, "set", "", &ledger, GasCosts::default())
+        .expect_err("Writing in a read-only execution should fail");
+    assert!(matches!(
+        err,
+        ExecutionError::ReadOnly { host_function, .. }
+            if host_function == "assembly_script_set_data_bytes"
+    ));
+    // The options apply to a read-only execution
+    let tracer = Arc::new(TestTracer::default());
+    let options = ExecutionOptions {
+        tracer: Some(tracer.clone()),
+        ..Default::default()
+    };
+    run_function_readonly_with_options(
+        &callee,
+        100_000,
+        "set",
+        "",
+        &ledger,
+        GasCosts::default(),
+        options,
+    )
+    .expect_err("Writing in a read-only execution should fail");
+    assert_eq!(
+        *tracer.0.lock().unwrap(),
+        vec![
+            r#"0 enter assembly_script_set_data_bytes [String("k"), Bytes([97, 102, 116, 101, 114])]"#,
+            "0 exit assembly_script_set_data_bytes false",
+        ]
+    );
+
+    // A static call is read-only
+    let err = run_test_function(&caller, "static_set", &ledger)
+        .expect_err("Writing in a static call should fail");
+    assert!(matches!(err, ExecutionError::ReadOnly { function, .. } if function == "set"));
+    assert_eq!(ledger.raw_get_data("k").unwrap(), b"before");
+}
+
+#[test]
+fn test_estimate_gas() {
+    let ledger = test_ledger();
+    ledger.raw_set_data("k", b"before").unwrap();
+    let mut data = TestData::default();
+    let (key, value) = (data.string("k"), data.bytes(b"after"));
+    let (address, handler) = (data.string("caller"), data.string("receive"));
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (import "massa" "assembly_script_generate_event" (func $event (param i32)))
+        (import "massa" "assembly_script_send_message" (func $send_message (param i32 i32 i64 i32 i64 i32 i64 i64 i64 i32)))
+        (func (export "set") (param i32)
+            (call $set (i32.const {key}) (i32.const {value}))
+            (call $event (i32.const {key}))
+            (call $send_message (i32.const {address}) (i32.const {handler})
+                (i64.const 1) (i32.const 0) (i64.const 2) (i32.const 0)
+                (i64.const 1000) (i64.const 1) (i64.const 100) (i32.const {key})))
+        (func (export "fail") (param i32)
+            unreachable)"#
+        ),
+    );
+    let estimate = estimate_gas(&module, 100_000, "set", "", &ledger, GasCosts::default())
+        .expect("Failed to estimate the gas");
+    assert_eq!(estimate.gas_used, estimate.min_limit);
+    // The dry runs are reverted, their events and messages dropped
+    assert_eq!(ledger.raw_get_data("k").unwrap(), b"before");
+    let state = ledger.state();
+    assert!(state.events.is_empty());
+    assert!(state.messages.is_empty());
+    assert_eq!(ledger.get_balance_for("caller").unwrap(), 1_000);
+
+    let run = |limit: u64| run_function(&module, limit, "set", "", &ledger, GasCosts::default());
+    run(estimate.min_limit - 1).expect_err("The estimated limit should be the smallest");
+    run(estimate.min_limit).expect("The estimated limit should be enough");
+
+    let err = estimate_gas(&module, 100_000, "fail", "", &ledger, GasCosts::default())
+        .expect_err("The estimation of a failing function should fail");
+    assert!(matches!(err, ExecutionError::Trap { .. }));
+}
+
+#[test]
+fn test_gas_report() {
+    let ledger = test_ledger();
+    let mut data = TestData::default();
+    let (key, value) = (data.string("k"), data.bytes(b"after"));
+    let callee = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (func (export "set") (param i32)
+            (call $set (i32.const {key}) (i32.const {value})))"#
+        ),
+    );
+    deploy(&ledger, "callee", &callee);
+    let mut data = TestData::default();
+    let call_set = data.call("callee", "set");
+    let caller = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (func (export "call") (param i32)
+            (drop (memory.grow (i32.const 1)))
+            (drop (call $call {call_set})))"#
+        ),
+    );
+    let options = ExecutionOptions {
+        gas_report: true,
+        ..Default::default()
+    };
+    let result = run_function_with_options(
+        &caller,
+        100_000,
+        "call",
+        "",
+        &ledger,
+        GasCosts::default(),
+        options,
+    )
+    .expect("Failed to run the function");
+    let report = result
+        .gas_report
+        .expect("The gas report should be returned");
+    assert_eq!(report.gas_used, result.gas_used);
+    assert_eq!(report.function, "call");
+    let call = &report.host_functions["assembly_script_call"];
+    assert_eq!((call.calls, call.gas), (1, GasCosts::default().call));
+    // The initial page and the page allocated by `memory.grow`
+    let memory_page = GasCosts::default().memory_page;
+    assert_eq!(report.memory_pages, 2 * memory_page);
+
+    // The gas of the callee is reported apart
+    let [callee_report] = &report.calls[..] else {
+        panic!("The call should be reported");
+    };
+    assert_eq!(callee_report.address.as_deref(), Some("callee"));
+    assert_eq!(callee_report.function, "set");
+    let set = &callee_report.host_functions["assembly_script_set_data_bytes"];
+    assert_eq!((set.calls, set.bytes), (1, 6));
+    let host_functions: u64 = callee_report.host_functions.values().map(|f| f.gas).sum();
+    assert_eq!(callee_report.memory_pages, memory_page);
+    assert_eq!(
+        callee_report.instructions + callee_report.memory_pages + host_functions,
+        callee_report.gas_used
+    );
+    assert_eq!(
+        report.instructions + report.memory_pages + call.gas + callee_report.gas_used,
+        report.gas_used
+    );
+
+    // Without the option, there is no report
+    let result = run_test_function(&caller, "call", &ledger).expect("Failed to run the function");
+    assert_eq!(result.gas_report, None);
+}
+
+/// Tracer recording what it's notified of
+#[derive(Default)]
+struct TestTracer(Mutex<Vec<String>>);
+
+impl Tracer for TestTracer {
+    fn enter_host_function(&self, name: &str, args: &[ReturnValue], _gas: u64, depth: u32) {
+        let trace = format!("{} enter {} {:?}", depth, name, args);
+        self.0.lock().unwrap().push(trace);
+    }
+
+    fn exit_host_function(
+        &self,
+        name: &str,
+        result: Result<&ReturnValue, &str>,
+        _gas: u64,
+        depth: u32,
+    ) {
+        let trace = format!("{} exit {} {:?}", depth, name, result.is_ok());
+        self.0.lock().unwrap().push(trace);
+    }
+
+    fn enter_call(&self, address: &str, function: &str, _param: &str, _gas: u64, depth: u32) {
+        let trace = format!("{} call {}::{}", depth, address, function);
+        self.0.lock().unwrap().push(trace);
+    }
+
+    fn exit_call(
+        &self,
+        address: &str,
+        function: &str,
+        result: Result<&ExecutionResult, &ExecutionError>,
+        depth: u32,
+    ) {
+        let trace = format!(
+            "{} return {}::{} {}",
+            depth,
+            address,
+            function,
+            result.is_ok()
+        );
+        self.0.lock().unwrap().push(trace);
+    }
+}
+
+#[test]
+fn test_tracer() {
+    let ledger = test_ledger();
+    let mut data = TestData::default();
+    let (key, value) = (data.string("k"), data.bytes(&[1, 2]));
+    let callee = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_data_bytes" (func $set (param i32 i32)))
+        (func (export "set") (param i32)
+            (call $set (i32.const {key}) (i32.const {value})))"#
+        ),
+    );
+    deploy(&ledger, "callee", &callee);
+    let mut data = TestData::default();
+    let call_set = data.call("callee", "set");
+    let caller = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (func (export "call") (param i32)
+            (drop (call $call {call_set})))"#
+        ),
+    );
+    let tracer = Arc::new(TestTracer::default());
+    let options = ExecutionOptions {
+        tracer: Some(tracer.clone()),
+        ..Default::default()
+    };
+    run_function_with_options(
+        &caller,
+        100_000,
+        "call",
+        "",
+        &ledger,
+        GasCosts::default(),
+        options,
+    )
+    .expect("Failed to run the function");
+    assert_eq!(
+        *tracer.0.lock().unwrap(),
+        vec![
+            r#"0 enter assembly_script_call [String("callee"), String("set"), String(""), Int(0)]"#,
+            "1 call callee::set",
+            r#"1 enter assembly_script_set_data_bytes [String("k"), Bytes([1, 2])]"#,
+            "1 exit assembly_script_set_data_bytes true",
+            "1 return callee::set true",
+            "0 exit assembly_script_call true",
+        ]
+    );
+}
+
+#[test]
+fn test_events() {
+    let ledger = test_ledger();
+    let mut data = TestData::default();
+    let (topics, bytes) = (data.string(r#"["a","b"]"#), data.bytes(&[1, 2]));
+    let hi = data.string("hi");
+    let five_topics = data.string(r#"["","","","",""]"#);
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_generate_event" (func $event (param i32)))
+        (import "massa" "assembly_script_generate_event_with_topics" (func $event_with_topics (param i32 i32)))
+        (func (export "emit") (param i32)
+            (call $event_with_topics (i32.const {topics}) (i32.const {bytes}))
+            (call $event (i32.const {hi})))
+        (func (export "too_many_topics") (param i32)
+            (call $event_with_topics (i32.const {five_topics}) (i32.const {bytes})))"#
+        ),
+    );
+    let run = |function: &str| run_test_function(&module, function, &ledger);
+    run("emit").expect("Failed to emit the events");
+    let event = |index: usize| ledger.state().events[index].clone();
+    assert_eq!(
+        event(0),
+        ScEvent {
+            address: "caller".to_string(),
+            call_stack_index: 0,
+            index: 0,
+            topics: vec!["a".to_string(), "b".to_string()],
+            data: vec![1, 2],
+        }
+    );
+    assert_eq!(event(1).index, 1);
+    assert_eq!(event(1).topics, Vec::<String>::new());
+    assert_eq!(event(1).data, b"hi");
+
+    let err = run("too_many_topics").expect_err("An event has at most 4 topics");
+    assert!(matches!(err, ExecutionError::Interface { .. }));
+}
+
+#[test]
+fn test_validate_module() {
+    let gas_costs = GasCosts::default();
+    let validate = |module: &str| validate_module(module.as_bytes(), &gas_costs);
+    validate_module(&test_module(&TestData::default(), ""), &gas_costs)
+        .expect("The module should be valid");
+    assert!(matches!(
+        validate_module(b"not a module", &gas_costs),
+        Err(ValidationError::InvalidBytecode(_))
+    ));
+    // Floats are only refused in the strict determinism mode
+    let floats = "(module (func (result f64) f64.const 1))";
+    validate(floats).expect("Floats are allowed by default");
+    let strict = GasCosts {
+        reject_floats: true,
+        ..GasCosts::default()
+    };
+    assert_eq!(
+        validate_module(floats.as_bytes(), &strict),
+        Err(ValidationError::FloatInstruction { function: 0 })
+    );
+    assert_eq!(
+        validate(r#"(module (import "wasi" "exit" (func)))"#),
+        Err(ValidationError::UnknownImport {
+            module: "wasi".to_string(),
+            field: "exit".to_string(),
+        })
+    );
+    assert_eq!(
+        validate(r#"(module (import "env" "memory" (memory 1)))"#),
+        Err(ValidationError::ImportedMemory)
+    );
+    assert_eq!(
+        validate("(module (func $init) (start $init))"),
+        Err(ValidationError::StartFunction)
+    );
+    let reserved = r#"(module (func (export "__massa_stack_height")))"#;
+    assert_eq!(
+        validate(reserved),
+        Err(ValidationError::ReservedName(
+            "__massa_stack_height".to_string()
+        ))
+    );
+    // A module that hasn't been validated is refused when it's compiled
+    let ledger = test_ledger();
+    let err = run_test_function(reserved.as_bytes(), "__massa_stack_height", &ledger)
+        .expect_err("A reserved export should be refused");
+    assert!(matches!(err, ExecutionError::InvalidBytecode { .. }));
+    assert!(err.to_string().contains("reserved"));
+    assert_eq!(
+        validate("(module (memory 65))"),
+        Err(ValidationError::TooManyPages { count: 65, max: 64 })
+    );
+    let gas_costs = GasCosts {
+        validation: ValidationLimits {
+            max_functions: 1,
+            max_globals: 1,
+            max_locals: 2,
+            max_table_entries: 2,
+            allow_start_function: true,
+        },
+        max_number_of_pages: 0,
+        ..GasCosts::default()
+    };
+    let validate = |module: &str| validate_module(module.as_bytes(), &gas_costs);
+    validate("(module (func $init (local i32 i64)) (start $init))")
+        .expect("The module should be within the limits");
+    assert_eq!(
+        validate(r#"(module (import "env" "f" (func)) (func))"#),
+        Err(ValidationError::TooManyFunctions { count: 2, max: 1 })
+    );
+    assert_eq!(
+        validate("(module (global i32 (i32.const 0)) (global i32 (i32.const 0)))"),
+        Err(ValidationError::TooManyGlobals { count: 2, max: 1 })
+    );
+    assert_eq!(
+        validate("(module (func (local i32 i32 i32)))"),
+        Err(ValidationError::TooManyLocals {
+            function: 0,
+            count: 3,
+            max: 2
+        })
+    );
+    assert_eq!(
+        validate("(module (table 3 funcref))"),
+        Err(ValidationError::TooManyTableEntries { count: 3, max: 2 })
+    );
+    assert_eq!(
+        validate(r#"(module (memory 0) (data (i32.const 0) "a"))"#),
+        Err(ValidationError::DataSegmentTooLarge { size: 1, max: 0 })
+    );
+
+    // The bytecode given to `assembly_script_set_bytecode` is validated
+    let mut data = TestData::default();
+    let bytecode = data.string(&base64::encode(floats));
+    let module = test_module(
+        &data,
+        &format!(
+            r#"(import "massa" "assembly_script_set_bytecode" (func $set_bytecode (param i32)))
+        (func (export "set_bytecode") (param i32)
+            (call $set_bytecode (i32.const {bytecode})))"#
+        ),
+    );
+    let ledger = test_ledger();
+    let err = run_function(&module, 100_000, "set_bytecode", "", &ledger, strict)
+        .expect_err("A module with floats should be refused");
+    assert!(matches!(err, ExecutionError::Interface { .. }));
+    assert!(err.to_string().contains("Float instruction"));
+    assert!(ledger.get_module("caller").unwrap().is_empty());
+}
+
+#[test]
+fn test_timeout() {
+    let ledger = test_ledger();
+    let mut data = TestData::default();
+    let call_loop = data.call("caller", "loop");
+    let module = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (import "massa" "assembly_script_try_call" (func $try_call (param i32 i32 i32 i64 i32) (result i32)))
+        (func (export "noop") (param i32))
+        (func (export "loop") (param i32)
+            (loop (br 0)))
+        (func (export "call_loop") (param i32)
+            (drop (call $call {call_loop})))
+        (func (export "try_call_loop") (param i32)
+            (drop (call $try_call {call_loop} (i32.const 0))))"#
+        ),
+    );
+    deploy(&ledger, "caller", &module);
+    let run = |function: &str, timeout: Duration| {
+        let options = ExecutionOptions {
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+        run_function_with_options(
+            &module,
+            100_000_000,
+            function,
+            "",
+            &ledger,
+            GasCosts::default(),
+            options,
+        )
+    };
+
+    run("noop", Duration::from_secs(60)).expect("Failed to run noop");
+    // The timeout is decided by the execution, which doesn't check the
+    // deadline before it returns
+    run("noop", Duration::ZERO).expect("noop should finish before its first check");
+    match run("loop", Duration::ZERO) {
+        Err(ExecutionError::Timeout {
+            function,
+            remaining_gas,
+            timeout,
+        }) => {
+            assert_eq!(function, "loop");
+            assert!(remaining_gas > 0);
+            assert_eq!(timeout, Duration::ZERO);
+        }
+        result => panic!("loop should time out, got {:?}", result),
+    }
+    // The deadline is checked by the callee as well, and its timeout can't be
+    // caught by the caller
+    for function in ["call_loop", "try_call_loop"] {
+        let result = run(function, Duration::from_millis(50));
+        assert!(
+            matches!(result, Err(ExecutionError::Timeout { .. })),
+            "{} should time out, got {:?}",
+            function,
+            result
+        );
+    }
+}
+
+#[test]
+fn test_timeout_function_indices() {
+    let ledger = test_ledger();
+    // The deadline check is imported after `$get_remaining_gas`, every
+    // reference to `$one` and `$two` must be shifted
+    let module = test_module(
+        &TestData::default(),
+        r#"(import "massa" "assembly_script_get_remaining_gas" (func $get_remaining_gas (result i64)))
+        (type $constant (func (result i64)))
+        (table $table 2 funcref)
+        (elem (i32.const 0) $one $two)
+        (elem $passive func $two)
+        (global $ref funcref (ref.func $two))
+        (func $one (result i64) i64.const 1)
+        (func $two (result i64) i64.const 2)
+        (func (export "table") (param i32) (result i64)
+            (call_indirect (type $constant) (i32.const 1)))
+        (func (export "passive_element") (param i32) (result i64)
+            (table.init $passive (i32.const 0) (i32.const 0) (i32.const 1))
+            (call_indirect (type $constant) (i32.const 0)))
+        (func (export "ref_func_global") (param i32) (result i64)
+            (table.set $table (i32.const 0) (global.get $ref))
+            (call_indirect (type $constant) (i32.const 0)))
+        (func (export "ref_func") (param i32) (result i64)
+            (table.set $table (i32.const 0) (ref.func $two))
+            (call_indirect (type $constant) (i32.const 0)))"#,
+    );
+    let options = ExecutionOptions {
+        timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    for function in ["table", "passive_element", "ref_func_global", "ref_func"] {
+        let result = run_function_with_options(
+            &module,
+            100_000,
+            function,
+            "",
+            &ledger,
+            GasCosts::default(),
+            options.clone(),
+        )
+        .expect("Failed to run the function");
+        assert_eq!(result.ret, ReturnValue::Int(2), "{}", function);
+    }
+
+    // Only the executions with a timeout import the check of the deadline
+    let imports_check = |check_deadline: bool| {
+        let config = CompilationConfig {
+            check_deadline,
+            ..CompilationConfig::new(&GasCosts::default())
+        };
+        let compiled = compile_module(&module, &config).expect("Failed to compile the module");
+        let mut imports = compiled.imports();
+        imports.any(|import| import.name() == "__massa_check_deadline")
+    };
+    assert!(imports_check(true));
+    assert!(!imports_check(false));
+}
+
+#[test]
+fn test_mock_ledger() {
+    let ledger = MockLedger::with_caller("A0", 1_000);
+    let mut data = TestData::default();
+    let (address, store, empty) = (data.string("B"), data.string("store"), data.string(""));
+    let (key, value, hi) = (data.string("k"), data.string("v"), data.string("hi"));
+    let module = test_module(
+        &data,
+        &format!(
+            r#"{CALL_IMPORT}
+        (import "massa" "assembly_script_set_data" (func $set_data (param i32 i32)))
+        (import "massa" "assembly_script_generate_event" (func $event (param i32)))
+        (import "massa" "assembly_script_print" (func $print (param i32)))
+        (func (export "call_store") (param i32)
+            (drop (call $call (i32.const {address}) (i32.const {store}) (i32.const {empty}) (i64.const 10)))
+            (call $event (i32.const {hi}))
+            (call $print (i32.const {hi})))
+        (func (export "call_store_expensive") (param i32)
+            (drop (call $call (i32.const {address}) (i32.const {store}) (i32.const {empty}) (i64.const 5000))))
+        (func (export "store") (param i32)
+            (call $set_data (i32.const {key}) (i32.const {value})))"#
+        ),
+    );
+    deploy(&ledger, "B", &module);
+    let run = |function: &str| run_test_function(&module, function, &ledger);
+
+    run("call_store").expect("Failed to call store");
+    assert_eq!(ledger.get_balance_for("A0").unwrap(), 990);
+    assert_eq!(ledger.get_balance_for("B").unwrap(), 10);
+    assert!(ledger.has_data_for("B", "k").unwrap());
+    assert!(!ledger.has_data_for("A0", "k").unwrap());
+    assert_eq!(ledger.get_call_stack().unwrap(), vec!["A0".to_string()]);
+    let state = ledger.state();
+    assert_eq!(state.events.len(), 1);
+    assert_eq!(state.events[0].address, "A0");
+    assert_eq!(state.prints, vec!["hi".to_string()]);
+    let err = run("call_store_expensive").expect_err("A0 doesn't have the coins");
+    assert!(matches!(err, ExecutionError::Interface { .. }));
+    assert_eq!(ledger.get_balance_for("A0").unwrap(), 990);
+    assert_eq!(ledger.get_call_stack().unwrap(), vec!["A0".to_string()]);
+
+    // The state is saved and loaded as JSON
+    let path = std::env::temp_dir().join(format!("mock_ledger_{}.json", std::process::id()));
+    ledger.save(&path).unwrap();
+    let loaded = MockLedger::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap().state(), ledger.state());
+    let json = ledger.to_json().unwrap();
+    assert_eq!(
+        MockLedger::from_json(&json).unwrap().state(),
+        ledger.state()
+    );
 }
@@ -0,0 +1,125 @@
+//! Tracing of the host functions and of the nested calls of an execution
+//!
+//! A `Tracer` is attached to an execution with `ExecutionOptions::tracer`,
+//! and given to the modules it calls.
+
+use crate::env::{get_remaining_points, Env};
+use crate::error::ExecutionError;
+use crate::execution_impl::read_return_value;
+use crate::types::{ExecutionResult, ReturnValue};
+use as_ffi_bindings::{Read, StringPtr};
+use wasmer::RuntimeError;
+
+/// Notified of the host functions and of the nested calls of an execution
+///
+/// The arguments and the results of the host functions are decoded: the
+/// AssemblyScript strings and `ArrayBuffer` are read from the memory, the
+/// numbers given as `ReturnValue::Int`. The depth is the number of calls the
+/// execution is nested in, 0 for the top level.
+#[allow(unused_variables)]
+pub trait Tracer: Send + Sync {
+    /// A host function is called, with `gas` remaining
+    fn enter_host_function(&self, name: &str, args: &[ReturnValue], gas: u64, depth: u32) {}
+
+    /// A host function returned, `gas` remaining after it
+    fn exit_host_function(
+        &self,
+        name: &str,
+        result: Result<&ReturnValue, &str>,
+        gas: u64,
+        depth: u32,
+    ) {
+    }
+
+    /// A module is called with at most `gas`
+    fn enter_call(&self, address: &str, function: &str, param: &str, gas: u64, depth: u32) {}
+
+    /// A called module returned
+    fn exit_call(
+        &self,
+        address: &str,
+        function: &str,
+        result: Result<&ExecutionResult, &ExecutionError>,
+        depth: u32,
+    ) {
+    }
+}
+
+/// Register a host function, traced if the execution has a tracer
+///
+/// The arguments and the result are described by their kind: `ptr` for the
+/// pointer to an AssemblyScript object, `str` for a `StringPtr`, `i32`,
+/// `i64`, or `()` for no result.
+macro_rules! host_function {
+    ($store:expr, $env:expr, $name:literal, $function:path, ($($arg:ident: $kind:tt),*) -> $ret:tt) => {
+        wasmer::Function::new_native_with_env(
+            $store,
+            $env.for_host_function($name),
+            |env: &$crate::env::Env, $($arg: host_function!(@type $kind)),*|
+                -> $crate::abi_impl::ABIResult<host_function!(@type $ret)> {
+                if env.tracer.is_none() {
+                    return $function(env, $($arg),*);
+                }
+                let args = [$(host_function!(@value env, $kind, $arg)),*];
+                $crate::tracer::enter_host_function(env, &args);
+                let result = $function(env, $($arg),*);
+                $crate::tracer::exit_host_function(
+                    env,
+                    result.as_ref().map(|ret| host_function!(@value env, $ret, *ret)),
+                );
+                result
+            },
+        )
+    };
+    (@type ptr) => { i32 };
+    (@type str) => { as_ffi_bindings::StringPtr };
+    (@type i32) => { i32 };
+    (@type i64) => { i64 };
+    (@type ()) => { () };
+    (@value $env:ident, ptr, $value:expr) => { $crate::tracer::decode_pointer($env, $value) };
+    (@value $env:ident, str, $value:expr) => { $crate::tracer::decode_string($env, $value) };
+    (@value $env:ident, i32, $value:expr) => { $crate::types::ReturnValue::Int($value as i64) };
+    (@value $env:ident, i64, $value:expr) => { $crate::types::ReturnValue::Int($value) };
+    (@value $env:ident, (), $value:expr) => {{
+        let _ = $value;
+        $crate::types::ReturnValue::Empty
+    }};
+}
+pub(crate) use host_function;
+
+pub(crate) fn enter_host_function(env: &Env, args: &[ReturnValue]) {
+    if let Some(tracer) = &env.tracer {
+        let gas = get_remaining_points(env).unwrap_or_default();
+        tracer.enter_host_function(env.host_function, args, gas, env.context.call_depth);
+    }
+}
+
+pub(crate) fn exit_host_function(env: &Env, result: Result<ReturnValue, &RuntimeError>) {
+    if let Some(tracer) = &env.tracer {
+        let gas = get_remaining_points(env).unwrap_or_default();
+        let result = result.map_err(|err| err.message());
+        let result = match &result {
+            Ok(ret) => Ok(ret),
+            Err(message) => Err(message.as_str()),
+        };
+        tracer.exit_host_function(env.host_function, result, gas, env.context.call_depth);
+    }
+}
+
+/// Read the AssemblyScript object at the given offset, or return the offset
+/// if it can't be read
+pub(crate) fn decode_pointer(env: &Env, offset: i32) -> ReturnValue {
+    env.wasm_env
+        .memory
+        .get_ref()
+        .and_then(|memory| read_return_value(memory, offset).ok())
+        .unwrap_or(ReturnValue::Int(offset as i64))
+}
+
+pub(crate) fn decode_string(env: &Env, ptr: StringPtr) -> ReturnValue {
+    env.wasm_env
+        .memory
+        .get_ref()
+        .and_then(|memory| ptr.read(memory).ok())
+        .map_or(ReturnValue::Int(ptr.offset() as i64), ReturnValue::String)
+}
@@ -1,3 +1,5 @@
+//! Limitation of the memory
+
 use std::ptr::NonNull;
 use std::sync::Arc;
 
@@ -7,8 +9,6 @@ use wasmer::{
     MemoryType, Pages, TableType, Tunables,
 };
 
-///! Limitation of the memory
-
 /// A custom tunables that allows you to set a memory limit.
 ///
 /// After adjusting the memory limits, it delegates all other logic
@@ -1,12 +1,70 @@
+use crate::gas_report::GasReport;
+use crate::tracer::Tracer;
 use anyhow::{bail, Result};
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Value returned by the function of a module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReturnValue {
+    /// The function returned nothing
+    Empty,
+    /// Number returned by `main`, or by a function returning an `i64`
+    Int(i64),
+    /// AssemblyScript `string`
+    String(String),
+    /// AssemblyScript `ArrayBuffer`
+    Bytes(Vec<u8>),
+}
 
 /// That's what is returned when a module is executed correctly since the end
-pub(crate) struct Response {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecutionResult {
     /// returned value from the module call
-    pub ret: String,
+    pub ret: ReturnValue,
+    /// number of gas consumed by the execution
+    pub gas_used: u64,
     /// number of gas that remain after the execution (metering)
     pub remaining_gas: u64,
+    /// breakdown of the gas used, if requested by `ExecutionOptions`
+    pub gas_report: Option<GasReport>,
+}
+
+/// Event emitted by a module
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScEvent {
+    /// Address of the module emitting the event
+    pub address: String,
+    /// Index of the module in the call stack
+    pub call_stack_index: usize,
+    /// Order of the event among the events of the execution, nested calls
+    /// included
+    pub index: u64,
+    /// Indexed topics, used to filter the events
+    pub topics: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+/// Options of `run_main_with_options` and `run_function_with_options`
+#[derive(Clone, Default)]
+pub struct ExecutionOptions {
+    /// Return a `GasReport` in the `ExecutionResult`
+    pub gas_report: bool,
+    /// Notified of the host functions and of the nested calls
+    pub tracer: Option<Arc<dyn Tracer>>,
+    /// Wall-clock time after which the execution fails with
+    /// `ExecutionError::Timeout`
+    pub timeout: Option<Duration>,
+}
+
+/// Whether a module can be called while it is already on the call stack
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReentrancyPolicy {
+    #[default]
+    Allow,
+    /// The call fails with `ExecutionError::Reentrancy`
+    Deny,
 }
 
 pub trait InterfaceClone {
@@ -37,6 +95,20 @@ pub trait Interface: Send + Sync + InterfaceClone {
         unimplemented!("finish_call")
     }
 
+    /// Finish a call that failed, its changes must be discarded
+    ///
+    /// Defaults to `finish_call`, see `JournaledInterface` to revert the
+    /// changes made through the interface.
+    fn revert_call(&self) -> Result<()> {
+        self.finish_call()
+    }
+
+    /// Reentrancy policy of the module at the given address, checked against
+    /// `get_call_stack` before each call to this module
+    fn get_reentrancy_policy(&self, address: &str) -> Result<ReentrancyPolicy> {
+        Ok(ReentrancyPolicy::Allow)
+    }
+
     /// Requires the module in the given address
     fn get_module(&self, address: &str) -> Result<Vec<u8>> {
         unimplemented!("get_module")
@@ -90,6 +162,21 @@ pub trait Interface: Send + Sync + InterfaceClone {
         unimplemented!("create_module")
     }
 
+    /// Delete the module created at the given address, used to revert a
+    /// `create_module`
+    ///
+    /// Fails by default: a `JournaledInterface` then can't revert a call that
+    /// created a module, and the execution fails.
+    fn delete_module(&self, address: &str) -> Result<()> {
+        bail!("unimplemented function delete_module in interface")
+    }
+
+    /// Called when a module aborts, before the execution ends with an
+    /// `ExecutionError::Abort` holding the same fields
+    fn on_abort(&self, message: &str, filename: &str, line: i32, col: i32) -> Result<()> {
+        Ok(())
+    }
+
     /// Print function for examples
     fn print(&self, message: &str) -> Result<()> {
         unimplemented!("print")
@@ -140,6 +227,18 @@ pub trait Interface: Send + Sync + InterfaceClone {
         unimplemented!("has_data_for")
     }
 
+    /// Returns the keys of the datastore of the current address that start
+    /// with the given prefix
+    fn get_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        bail!("unimplemented function get_keys in interface")
+    }
+
+    /// Returns the keys of the datastore of an arbitrary address that start
+    /// with the given prefix
+    fn get_keys_for(&self, address: &str, prefix: &str) -> Result<Vec<String>> {
+        bail!("unimplemented function get_keys_for in interface")
+    }
+
     // Hash data
     fn hash(&self, data: &[u8]) -> Result<String> {
         unimplemented!("hash")
@@ -199,8 +298,8 @@ pub trait Interface: Send + Sync + InterfaceClone {
         unimplemented!("get_call_stack")
     }
 
-    // TODO should be a SCEvent
-    fn generate_event(&self, _event: String) -> Result<()> {
+    /// Emit an event, see `ScEvent`
+    fn generate_event(&self, event: ScEvent) -> Result<()> {
         unimplemented!("generate_event")
     }
 
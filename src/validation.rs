@@ -0,0 +1,404 @@
+//! Static validation of the bytecode of a module before it's deployed
+//!
+//! `validate_module` is called by `assembly_script_create_sc` and the
+//! `assembly_script_set_bytecode*` functions before the bytecode is given to
+//! the `Interface`, so that a module that can't run is refused when it's
+//! deployed and not when it's called for the first time.
+
+use crate::settings::{self, GasCosts};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use wasmer::wasmparser::{
+    DataKind, ImportSectionEntryType, MemoryType, Operator, Parser, Payload, Validator,
+};
+
+/// Size of a webassembly page in bytes
+const PAGE_SIZE: u64 = 65_536;
+
+/// Namespaces from which a module can import
+const IMPORT_NAMESPACES: [&str; 2] = ["env", "massa"];
+
+/// Limits of a module checked by `validate_module`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationLimits {
+    /// Maximum number of functions, imported or defined
+    pub max_functions: u32,
+    /// Maximum number of globals, imported or defined
+    pub max_globals: u32,
+    /// Maximum number of locals of a function, parameters excluded
+    pub max_locals: u32,
+    /// Maximum number of entries of a table
+    pub max_table_entries: u32,
+    /// Whether the module can have a `start` function, which the runtime
+    /// calls before the executed function: AssemblyScript initializes its
+    /// globals with it, so the modules it builds are refused by default
+    pub allow_start_function: bool,
+}
+
+impl Default for ValidationLimits {
+    fn default() -> Self {
+        ValidationLimits {
+            max_functions: 8192,
+            max_globals: 1024,
+            max_locals: 1024,
+            max_table_entries: 4096,
+            allow_start_function: false,
+        }
+    }
+}
+
+/// Reason why a module is refused by `validate_module`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The bytecode isn't a valid webassembly module
+    InvalidBytecode(String),
+    /// A function uses a float instruction, floats aren't deterministic, see
+    /// `GasCosts::reject_floats`
+    FloatInstruction {
+        function: u32,
+    },
+    /// An import isn't in the `env` or `massa` namespace
+    UnknownImport {
+        module: String,
+        field: String,
+    },
+    /// The memory is imported instead of being defined by the module
+    ImportedMemory,
+    /// The module has a `start` function, see
+    /// `ValidationLimits::allow_start_function`
+    StartFunction,
+    TooManyFunctions {
+        count: u32,
+        max: u32,
+    },
+    TooManyGlobals {
+        count: u32,
+        max: u32,
+    },
+    TooManyLocals {
+        function: u32,
+        count: u64,
+        max: u32,
+    },
+    TooManyTableEntries {
+        count: u32,
+        max: u32,
+    },
+    /// The initial memory exceeds `GasCosts::max_number_of_pages`
+    TooManyPages {
+        count: u64,
+        max: u32,
+    },
+    /// A data segment is larger than `GasCosts::max_number_of_pages`
+    DataSegmentTooLarge {
+        size: usize,
+        max: u32,
+    },
+    /// An export or an import uses a name reserved by the runtime, see
+    /// `settings::RESERVED_PREFIX`
+    ReservedName(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidBytecode(error) => write!(f, "Invalid bytecode: {}", error),
+            ValidationError::FloatInstruction { function } => {
+                write!(f, "Float instruction in function {}", function)
+            }
+            ValidationError::UnknownImport { module, field } => {
+                write!(f, "Unknown import {}.{}", module, field)
+            }
+            ValidationError::ImportedMemory => write!(f, "The memory can't be imported"),
+            ValidationError::StartFunction => write!(f, "A module can't have a start function"),
+            ValidationError::TooManyFunctions { count, max } => {
+                write!(f, "{} functions, the maximum is {}", count, max)
+            }
+            ValidationError::TooManyGlobals { count, max } => {
+                write!(f, "{} globals, the maximum is {}", count, max)
+            }
+            ValidationError::TooManyLocals {
+                function,
+                count,
+                max,
+            } => write!(
+                f,
+                "{} locals in function {}, the maximum is {}",
+                count, function, max
+            ),
+            ValidationError::TooManyTableEntries { count, max } => {
+                write!(f, "{} table entries, the maximum is {}", count, max)
+            }
+            ValidationError::TooManyPages { count, max } => {
+                write!(f, "{} memory pages, the maximum is {}", count, max)
+            }
+            ValidationError::DataSegmentTooLarge { size, max } => write!(
+                f,
+                "Data segment of {} bytes, larger than the {} pages of the memory",
+                size, max
+            ),
+            ValidationError::ReservedName(name) => {
+                write!(f, "The name {} is reserved by the runtime", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<wasmer::wasmparser::BinaryReaderError> for ValidationError {
+    fn from(err: wasmer::wasmparser::BinaryReaderError) -> Self {
+        ValidationError::InvalidBytecode(err.to_string())
+    }
+}
+
+/// Check that a module, in the binary or the text format, can be deployed
+///
+/// The module must be valid, without imports outside of the `env` and `massa`
+/// namespaces, imported memory or names reserved by the runtime, and stay within the limits of
+/// `GasCosts::validation` and `GasCosts::max_number_of_pages`. Float
+/// instructions are refused if `GasCosts::reject_floats` is set, as they
+/// would fail when the module is compiled.
+pub fn validate_module(bytecode: &[u8], gas_costs: &GasCosts) -> Result<(), ValidationError> {
+    let limits = &gas_costs.validation;
+    let max_pages = gas_costs.max_number_of_pages;
+    let bytecode = wasmer::wat2wasm(bytecode)
+        .map_err(|err| ValidationError::InvalidBytecode(err.to_string()))?;
+    Validator::new().validate_all(&bytecode)?;
+
+    let (mut functions, mut globals) = (0, 0);
+    // Index of the next function body, the imported functions come first
+    let mut function = 0;
+    for payload in Parser::new(0).parse_all(&bytecode) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    check_name(import.field.unwrap_or_default())?;
+                    if !IMPORT_NAMESPACES.contains(&import.module) {
+                        return Err(ValidationError::UnknownImport {
+                            module: import.module.to_string(),
+                            field: import.field.unwrap_or_default().to_string(),
+                        });
+                    }
+                    match import.ty {
+                        ImportSectionEntryType::Function(_) => functions += 1,
+                        ImportSectionEntryType::Global(_) => globals += 1,
+                        ImportSectionEntryType::Memory(_) => {
+                            return Err(ValidationError::ImportedMemory)
+                        }
+                        ImportSectionEntryType::Table(table) => {
+                            check_table_entries(table.limits.initial, limits)?
+                        }
+                        _ => (),
+                    }
+                }
+                function = functions;
+            }
+            Payload::FunctionSection(reader) => functions += reader.get_count(),
+            Payload::GlobalSection(reader) => globals += reader.get_count(),
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    check_name(export?.field)?;
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader {
+                    check_table_entries(table?.limits.initial, limits)?;
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    let pages = match memory? {
+                        MemoryType::M32 { limits, .. } => limits.initial as u64,
+                        MemoryType::M64 { limits, .. } => limits.initial,
+                    };
+                    if pages > max_pages as u64 {
+                        return Err(ValidationError::TooManyPages {
+                            count: pages,
+                            max: max_pages,
+                        });
+                    }
+                }
+            }
+            Payload::StartSection { .. } if !limits.allow_start_function => {
+                return Err(ValidationError::StartFunction)
+            }
+            Payload::DataSection(reader) => {
+                for data in reader {
+                    let data = data?;
+                    if matches!(data.kind, DataKind::Active { .. })
+                        && data.data.len() as u64 > max_pages as u64 * PAGE_SIZE
+                    {
+                        return Err(ValidationError::DataSegmentTooLarge {
+                            size: data.data.len(),
+                            max: max_pages,
+                        });
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut locals = 0;
+                let mut reader = body.get_locals_reader()?;
+                for _ in 0..reader.get_count() {
+                    locals += reader.read()?.0 as u64;
+                }
+                if locals > limits.max_locals as u64 {
+                    return Err(ValidationError::TooManyLocals {
+                        function,
+                        count: locals,
+                        max: limits.max_locals,
+                    });
+                }
+                if gas_costs.reject_floats {
+                    for operator in body.get_operators_reader()? {
+                        if is_float(&operator?) {
+                            return Err(ValidationError::FloatInstruction { function });
+                        }
+                    }
+                }
+                function += 1;
+            }
+            _ => (),
+        }
+    }
+    if functions > limits.max_functions {
+        return Err(ValidationError::TooManyFunctions {
+            count: functions,
+            max: limits.max_functions,
+        });
+    }
+    if globals > limits.max_globals {
+        return Err(ValidationError::TooManyGlobals {
+            count: globals,
+            max: limits.max_globals,
+        });
+    }
+    Ok(())
+}
+
+/// Check that the exports and the imports of a module in the binary format
+/// don't use the names reserved by the runtime
+///
+/// Called when a module is compiled, as it may not have been validated.
+pub(crate) fn check_reserved_names(bytecode: &[u8]) -> Result<(), ValidationError> {
+    for payload in Parser::new(0).parse_all(bytecode) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    check_name(import?.field.unwrap_or_default())?;
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    check_name(export?.field)?;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), ValidationError> {
+    if name.starts_with(settings::RESERVED_PREFIX) {
+        return Err(ValidationError::ReservedName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn check_table_entries(entries: u32, limits: &ValidationLimits) -> Result<(), ValidationError> {
+    if entries > limits.max_table_entries {
+        return Err(ValidationError::TooManyTableEntries {
+            count: entries,
+            max: limits.max_table_entries,
+        });
+    }
+    Ok(())
+}
+
+/// Whether an instruction uses floats: the float arithmetic, loads, stores
+/// and constants, and the conversions from or to floats
+pub(crate) fn is_float(operator: &Operator) -> bool {
+    use Operator::*;
+    matches!(
+        operator,
+        F32Load { .. }
+            | F64Load { .. }
+            | F32Store { .. }
+            | F64Store { .. }
+            | F32Const { .. }
+            | F64Const { .. }
+            | F32Eq
+            | F32Ne
+            | F32Lt
+            | F32Gt
+            | F32Le
+            | F32Ge
+            | F64Eq
+            | F64Ne
+            | F64Lt
+            | F64Gt
+            | F64Le
+            | F64Ge
+            | F32Abs
+            | F32Neg
+            | F32Ceil
+            | F32Floor
+            | F32Trunc
+            | F32Nearest
+            | F32Sqrt
+            | F32Add
+            | F32Sub
+            | F32Mul
+            | F32Div
+            | F32Min
+            | F32Max
+            | F32Copysign
+            | F64Abs
+            | F64Neg
+            | F64Ceil
+            | F64Floor
+            | F64Trunc
+            | F64Nearest
+            | F64Sqrt
+            | F64Add
+            | F64Sub
+            | F64Mul
+            | F64Div
+            | F64Min
+            | F64Max
+            | F64Copysign
+            | I32TruncF32S
+            | I32TruncF32U
+            | I32TruncF64S
+            | I32TruncF64U
+            | I64TruncF32S
+            | I64TruncF32U
+            | I64TruncF64S
+            | I64TruncF64U
+            | F32ConvertI32S
+            | F32ConvertI32U
+            | F32ConvertI64S
+            | F32ConvertI64U
+            | F32DemoteF64
+            | F64ConvertI32S
+            | F64ConvertI32U
+            | F64ConvertI64S
+            | F64ConvertI64U
+            | F64PromoteF32
+            | I32ReinterpretF32
+            | I64ReinterpretF64
+            | F32ReinterpretI32
+            | F64ReinterpretI64
+            | I32TruncSatF32S
+            | I32TruncSatF32U
+            | I32TruncSatF64S
+            | I32TruncSatF64U
+            | I64TruncSatF32S
+            | I64TruncSatF32U
+            | I64TruncSatF64S
+            | I64TruncSatF64U
+    )
+}